@@ -0,0 +1,352 @@
+//! Expand the command line inputs into files and summarize batch results.
+
+use crate::{Converted, format_bytes};
+use std::fs;
+use std::path::Path;
+
+/// Extensions picked up from directories and globs when `--include` isn't given.
+const VIDEO_EXTENSIONS: &[&str] = &[
+    "mp4", "mkv", "mov", "webm", "flv", "avi", "ts", "m4v", "wmv", "mpg", "mpeg", "3gp", "gif",
+    "apng",
+];
+
+/// Suffix of the files this tool writes, which are never picked up again.
+const OUTPUT_SUFFIX: &str = "_telegram";
+
+/// Extension filter for files found in directories and globs.
+pub struct Filter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String]) -> Filter {
+        let normalize = |exts: &[String]| {
+            exts.iter()
+                .map(|e| e.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|e| !e.is_empty())
+                .collect()
+        };
+        Filter {
+            include: normalize(include),
+            exclude: normalize(exclude),
+        }
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if name.starts_with('.') || is_output(path) {
+            return false;
+        }
+
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase)
+            .unwrap_or_default();
+        if self.exclude.contains(&ext) {
+            return false;
+        }
+        if self.include.is_empty() {
+            VIDEO_EXTENSIONS.contains(&ext.as_str())
+        } else {
+            self.include.contains(&ext)
+        }
+    }
+}
+
+/// Whether `path` looks like `clip_telegram.mp4` or `clip_telegram_part2.webm`.
+fn is_output(path: &Path) -> bool {
+    let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+        return false;
+    };
+    // Split outputs carry a part number after the suffix
+    let stem = match stem.rsplit_once("_part") {
+        Some((base, part)) if !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()) => base,
+        _ => stem,
+    };
+    stem.ends_with(OUTPUT_SUFFIX)
+}
+
+/// Turn files, directories and glob patterns into the list of files to
+/// convert. Files named explicitly are always kept, so a missing one is
+/// reported by the conversion itself.
+pub fn collect_inputs(
+    patterns: &[String],
+    recursive: bool,
+    filter: &Filter,
+) -> Result<Vec<String>, String> {
+    let mut inputs = Vec::new();
+
+    for pattern in patterns {
+        let path = Path::new(pattern);
+        let found = if path.is_dir() {
+            let mut files = Vec::new();
+            walk_dir(path, recursive, filter, &mut files)
+                .map_err(|e| format!("Cannot read directory '{}': {}", pattern, e))?;
+            files
+        } else if !path.exists() && is_glob(pattern) {
+            glob(pattern)
+                .into_iter()
+                .filter(|p| Path::new(p).is_file() && filter.matches(Path::new(p)))
+                .collect()
+        } else {
+            vec![pattern.clone()]
+        };
+
+        if found.is_empty() {
+            eprintln!("Warning: no video files found for '{}'", pattern);
+        }
+        for file in found {
+            if !inputs.contains(&file) {
+                inputs.push(file);
+            }
+        }
+    }
+
+    if inputs.is_empty() {
+        return Err("no input files to convert".to_string());
+    }
+    Ok(inputs)
+}
+
+fn walk_dir(
+    dir: &Path,
+    recursive: bool,
+    filter: &Filter,
+    files: &mut Vec<String>,
+) -> std::io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            let hidden = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with('.'));
+            if recursive && !hidden {
+                walk_dir(&path, recursive, filter, files)?;
+            }
+        } else if filter.matches(&path)
+            && let Some(path) = path.to_str()
+        {
+            files.push(path.to_string());
+        }
+    }
+    Ok(())
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Expand a shell-style pattern, matching each path component separately.
+fn glob(pattern: &str) -> Vec<String> {
+    let join = |base: &str, name: &str| match base {
+        "" => name.to_string(),
+        "/" => format!("/{}", name),
+        _ => format!("{}/{}", base, name),
+    };
+
+    let (mut matches, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec!["/".to_string()], rest),
+        None => (vec![String::new()], pattern),
+    };
+
+    for component in rest.split('/').filter(|c| !c.is_empty()) {
+        let mut next = Vec::new();
+        for base in &matches {
+            if !is_glob(component) {
+                next.push(join(base, component));
+                continue;
+            }
+
+            let dir = if base.is_empty() { "." } else { base.as_str() };
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            let mut names: Vec<String> = entries
+                .filter_map(|e| e.ok()?.file_name().into_string().ok())
+                .filter(|name| !name.starts_with('.') || component.starts_with('.'))
+                .filter(|name| wildcard_match(component.as_bytes(), name.as_bytes()))
+                .collect();
+            names.sort();
+            next.extend(names.iter().map(|name| join(base, name)));
+        }
+        matches = next.into_iter().filter(|m| Path::new(m).exists()).collect();
+    }
+
+    matches
+}
+
+/// Match `*`, `?` and `[...]` (with `!` or `^` negation and ranges).
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(b'*') => (0..=name.len()).any(|i| wildcard_match(&pattern[1..], &name[i..])),
+        Some(b'?') => !name.is_empty() && wildcard_match(&pattern[1..], &name[1..]),
+        Some(b'[') => {
+            let Some(close) = pattern.iter().skip(2).position(|&b| b == b']') else {
+                return name.first() == Some(&b'[') && wildcard_match(&pattern[1..], &name[1..]);
+            };
+            let class = &pattern[1..close + 2];
+            let Some(&c) = name.first() else {
+                return false;
+            };
+            let (negated, class) = match class.first() {
+                Some(b'!' | b'^') => (true, &class[1..]),
+                _ => (false, class),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    found |= (class[i]..=class[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    found |= class[i] == c;
+                    i += 1;
+                }
+            }
+            found != negated && wildcard_match(&pattern[close + 3..], &name[1..])
+        }
+        Some(&b) => name.first() == Some(&b) && wildcard_match(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Print one row per input and the totals of a batch run.
+pub fn print_summary(results: &[(String, Result<Converted, String>)]) {
+    println!("Summary:");
+    println!(
+        "  {:<2}  {:>10}  {:>10}  {:>10}  File",
+        "", "Input", "Output", "Saved"
+    );
+
+    let mut saved_total: i64 = 0;
+    let mut succeeded = 0;
+    for (input, result) in results {
+        match result {
+            Ok(converted) => {
+                let saved = converted.input_size as i64 - converted.output_size as i64;
+                saved_total += saved;
+                succeeded += 1;
+                println!(
+                    "  {:<2}  {:>10}  {:>10}  {:>10}  {}",
+                    "✓",
+                    format_bytes(converted.input_size),
+                    format_bytes(converted.output_size),
+                    format_signed_bytes(saved),
+                    input
+                );
+            }
+            Err(e) => {
+                println!(
+                    "  {:<2}  {:>10}  {:>10}  {:>10}  {} ({})",
+                    "✗", "-", "-", "-", input, e
+                );
+            }
+        }
+    }
+
+    println!(
+        "{} succeeded, {} failed, {} saved",
+        succeeded,
+        results.len() - succeeded,
+        format_signed_bytes(saved_total)
+    );
+}
+
+fn format_signed_bytes(bytes: i64) -> String {
+    if bytes < 0 {
+        format!("-{}", format_bytes(bytes.unsigned_abs()))
+    } else {
+        format_bytes(bytes as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        wildcard_match(pattern.as_bytes(), name.as_bytes())
+    }
+
+    #[test]
+    fn matches_stars_and_question_marks() {
+        assert!(matches("*.mp4", "clip.mp4"));
+        assert!(matches("*.mp4", ".mp4"));
+        assert!(matches("clip*", "clip"));
+        assert!(matches("*a*b*", "xxaxxbxx"));
+        assert!(!matches("*.mp4", "clip.mp4.part"));
+        assert!(matches("clip?.mov", "clip1.mov"));
+        assert!(!matches("clip?.mov", "clip.mov"));
+        assert!(!matches("clip?.mov", "clip12.mov"));
+        assert!(matches("exact", "exact"));
+        assert!(!matches("exact", "Exact"));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn matches_character_classes() {
+        assert!(matches("day[12].mp4", "day1.mp4"));
+        assert!(matches("day[12].mp4", "day2.mp4"));
+        assert!(!matches("day[12].mp4", "day3.mp4"));
+        assert!(matches("day[0-9].mp4", "day7.mp4"));
+        assert!(!matches("day[0-9].mp4", "dayx.mp4"));
+        assert!(matches("[a-cx-z]*", "yes"));
+        assert!(!matches("[a-cx-z]*", "no"));
+        // A dash at either end is literal
+        assert!(matches("a[-b]", "a-"));
+        assert!(matches("a[b-]", "a-"));
+        // A `]` right after the opening bracket is part of the class
+        assert!(matches("[]x]", "]"));
+        assert!(!matches("day[1]", "day"));
+    }
+
+    #[test]
+    fn negates_character_classes() {
+        assert!(matches("day[!12].mp4", "day3.mp4"));
+        assert!(!matches("day[!12].mp4", "day1.mp4"));
+        assert!(matches("day[^0-9].mp4", "dayx.mp4"));
+        assert!(!matches("day[^0-9].mp4", "day5.mp4"));
+    }
+
+    #[test]
+    fn takes_unclosed_brackets_literally() {
+        assert!(matches("clip[1", "clip[1"));
+        assert!(!matches("clip[1", "clip1"));
+    }
+
+    #[test]
+    fn recognizes_outputs() {
+        assert!(is_output(Path::new("clip_telegram.mp4")));
+        assert!(is_output(Path::new("dir/clip_telegram.webm")));
+        assert!(is_output(Path::new("clip_telegram_part2.mp4")));
+        assert!(!is_output(Path::new("clip.mp4")));
+        assert!(!is_output(Path::new("clip_telegram_part.mp4")));
+        assert!(!is_output(Path::new("clip_telegram_partx.mp4")));
+        assert!(!is_output(Path::new("telegram.mp4")));
+    }
+
+    #[test]
+    fn filters_by_extension() {
+        let default = Filter::new(&[], &[]);
+        assert!(default.matches(Path::new("a/clip.MP4")));
+        assert!(!default.matches(Path::new("notes.txt")));
+        assert!(!default.matches(Path::new(".hidden.mp4")));
+        assert!(!default.matches(Path::new("clip_telegram.mp4")));
+
+        let custom = Filter::new(
+            &[".TXT".to_string(), "mkv".to_string()],
+            &["mkv".to_string()],
+        );
+        assert!(custom.matches(Path::new("notes.txt")));
+        assert!(!custom.matches(Path::new("clip.mkv")));
+        assert!(!custom.matches(Path::new("clip.mp4")));
+    }
+}
@@ -0,0 +1,266 @@
+//! Video encoders and how generic quality, bitrate and speed settings map
+//! to each one's ffmpeg options.
+
+use crate::preset::Container;
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Highest CRF on the generic scale, which is libx264's.
+pub const MAX_CRF: u32 = 51;
+
+/// Telegram clients a codec can be sent to.
+const ALL_CLIENTS: &[&str] = &["Android", "iOS", "macOS", "Desktop", "Web"];
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum VideoCodec {
+    /// H.264 with libx264; plays on every Telegram client
+    H264,
+    /// H.265/HEVC with libx265; smaller, but only for Apple clients and Desktop
+    H265,
+    /// VP9 with libvpx-vp9
+    Vp9,
+    /// AV1 with SVT-AV1, the fast AV1 encoder
+    Av1,
+    /// AV1 with libaom, slower than SVT-AV1 but in more ffmpeg builds
+    Av1Aom,
+}
+
+/// How much time the encoder may spend per frame, named after the libx264
+/// presets.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Speed {
+    Ultrafast,
+    Superfast,
+    Veryfast,
+    Faster,
+    Fast,
+    Medium,
+    Slow,
+    Slower,
+    Veryslow,
+}
+
+impl Speed {
+    pub fn name(self) -> &'static str {
+        match self {
+            Speed::Ultrafast => "ultrafast",
+            Speed::Superfast => "superfast",
+            Speed::Veryfast => "veryfast",
+            Speed::Faster => "faster",
+            Speed::Fast => "fast",
+            Speed::Medium => "medium",
+            Speed::Slow => "slow",
+            Speed::Slower => "slower",
+            Speed::Veryslow => "veryslow",
+        }
+    }
+
+    /// Pick from `values`, which go from the fastest to the slowest setting.
+    fn pick(self, values: [u32; 9]) -> u32 {
+        values[self as usize]
+    }
+}
+
+/// Content the encoder is tuned for, named after the libx264 tunes.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Tune {
+    /// Live action
+    Film,
+    /// Cartoons and other flat, clean content
+    Animation,
+    /// Slideshows and mostly still images
+    Stillimage,
+    /// Keep film grain instead of smoothing it away
+    Grain,
+}
+
+impl Tune {
+    pub fn name(self) -> &'static str {
+        match self {
+            Tune::Film => "film",
+            Tune::Animation => "animation",
+            Tune::Stillimage => "stillimage",
+            Tune::Grain => "grain",
+        }
+    }
+}
+
+impl VideoCodec {
+    /// Name on the command line and in the config file
+    pub fn name(self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "h264",
+            VideoCodec::H265 => "h265",
+            VideoCodec::Vp9 => "vp9",
+            VideoCodec::Av1 => "av1",
+            VideoCodec::Av1Aom => "av1-aom",
+        }
+    }
+
+    /// Name of the format, for messages
+    pub fn label(self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "H.264",
+            VideoCodec::H265 => "H.265",
+            VideoCodec::Vp9 => "VP9",
+            VideoCodec::Av1 | VideoCodec::Av1Aom => "AV1",
+        }
+    }
+
+    /// ffmpeg encoder
+    pub fn encoder(self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "libx264",
+            VideoCodec::H265 => "libx265",
+            VideoCodec::Vp9 => "libvpx-vp9",
+            VideoCodec::Av1 => "libsvtav1",
+            VideoCodec::Av1Aom => "libaom-av1",
+        }
+    }
+
+    /// Codec name ffprobe reports for streams of this format
+    pub fn stream_name(self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "h264",
+            VideoCodec::H265 => "hevc",
+            VideoCodec::Vp9 => "vp9",
+            VideoCodec::Av1 | VideoCodec::Av1Aom => "av1",
+        }
+    }
+
+    /// Containers the format can be stored in.
+    pub fn containers(self) -> &'static [Container] {
+        match self {
+            VideoCodec::H264 | VideoCodec::H265 => &[Container::Mp4],
+            VideoCodec::Vp9 | VideoCodec::Av1 | VideoCodec::Av1Aom => {
+                &[Container::Mp4, Container::WebM]
+            }
+        }
+    }
+
+    /// Telegram clients that play the format inline. Apple devices decode
+    /// H.265 in hardware but have no VP9 or AV1 decoder to fall back on.
+    pub fn clients(self) -> &'static [&'static str] {
+        match self {
+            VideoCodec::H264 => ALL_CLIENTS,
+            VideoCodec::H265 => &["iOS", "macOS", "Desktop"],
+            VideoCodec::Vp9 | VideoCodec::Av1 | VideoCodec::Av1Aom => {
+                &["Android", "Desktop", "Web"]
+            }
+        }
+    }
+
+    pub fn plays_everywhere(self) -> bool {
+        self.clients().len() == ALL_CLIENTS.len()
+    }
+
+    /// The encoder's own CRF for `crf` on the libx264 scale, which the
+    /// presets and `--crf` use. The offsets give roughly the same visual
+    /// quality.
+    pub fn crf(self, crf: u32) -> u32 {
+        let (offset, max) = self.crf_range();
+        (crf + offset).min(max)
+    }
+
+    /// Highest CRF on the libx264 scale that still means something to the
+    /// encoder, the end of quality retries.
+    pub fn max_crf(self) -> u32 {
+        let (offset, max) = self.crf_range();
+        max - offset
+    }
+
+    /// How far the encoder's CRF is above libx264's, and its highest CRF.
+    fn crf_range(self) -> (u32, u32) {
+        match self {
+            VideoCodec::H264 => (0, 51),
+            VideoCodec::H265 => (5, 51),
+            VideoCodec::Vp9 | VideoCodec::Av1Aom => (8, 63),
+            VideoCodec::Av1 => (12, 63),
+        }
+    }
+
+    pub fn speed_args(self, speed: Speed) -> Vec<String> {
+        match self {
+            VideoCodec::H264 | VideoCodec::H265 => {
+                vec!["-preset".to_string(), speed.name().to_string()]
+            }
+            // Medium matches libvpx's own default of 1
+            VideoCodec::Vp9 => vec![
+                "-deadline".to_string(),
+                "good".to_string(),
+                "-cpu-used".to_string(),
+                speed.pick([5, 4, 4, 3, 2, 1, 1, 0, 0]).to_string(),
+                "-row-mt".to_string(),
+                "1".to_string(),
+            ],
+            VideoCodec::Av1 => vec![
+                "-preset".to_string(),
+                speed.pick([12, 11, 10, 9, 8, 6, 4, 3, 2]).to_string(),
+            ],
+            VideoCodec::Av1Aom => vec![
+                "-cpu-used".to_string(),
+                speed.pick([8, 7, 6, 5, 5, 4, 3, 2, 1]).to_string(),
+                "-row-mt".to_string(),
+                "1".to_string(),
+            ],
+        }
+    }
+
+    /// Options for `tune`, empty when the encoder has nothing it maps to.
+    pub fn tune_args(self, tune: Tune) -> Vec<String> {
+        match (self, tune) {
+            (VideoCodec::H264, _) | (VideoCodec::H265, Tune::Animation | Tune::Grain) => {
+                vec!["-tune".to_string(), tune.name().to_string()]
+            }
+            (VideoCodec::Vp9, Tune::Film) => {
+                vec!["-tune-content".to_string(), "film".to_string()]
+            }
+            // The other encoders have a mode for screen content instead
+            (VideoCodec::Vp9, Tune::Stillimage) => {
+                vec!["-tune-content".to_string(), "screen".to_string()]
+            }
+            (VideoCodec::Av1, Tune::Stillimage) => {
+                vec!["-svtav1-params".to_string(), "scm=1".to_string()]
+            }
+            (VideoCodec::Av1Aom, Tune::Stillimage) => {
+                vec!["-aom-params".to_string(), "tune-content=screen".to_string()]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn supports_tune(self, tune: Tune) -> bool {
+        !self.tune_args(tune).is_empty()
+    }
+
+    /// Stream tag to write to MP4; Apple players only take H.265 tagged
+    /// `hvc1`.
+    pub fn tag(self) -> Option<&'static str> {
+        match self {
+            VideoCodec::H265 => Some("hvc1"),
+            _ => None,
+        }
+    }
+
+    pub fn supports_two_pass(self) -> bool {
+        self != VideoCodec::Av1
+    }
+
+    /// Options for pass `pass` of a two-pass encode that logs to `log_file`.
+    pub fn pass_args(self, pass: u32, log_file: &Path) -> Vec<OsString> {
+        match self {
+            // libx265 ignores -pass and takes the pass in its own parameters
+            VideoCodec::H265 => {
+                let mut params = OsString::from(format!("pass={}:stats=", pass));
+                params.push(log_file);
+                vec!["-x265-params".into(), params]
+            }
+            _ => vec![
+                "-pass".into(),
+                pass.to_string().into(),
+                "-passlogfile".into(),
+                log_file.into(),
+            ],
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Decide which input streams Telegram Mobile can play as they are.
+
+use crate::codec::VideoCodec;
+use crate::framerate;
+use crate::h264;
+use crate::preset::{Container, Preset};
+use crate::probe::{AudioStream, MediaInfo, VideoStream};
+use crate::rotate;
+use crate::{Args, format_bytes};
+use std::fmt;
+
+/// What to do with one stream of the input.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamAction {
+    /// Stream copy, the input is already compatible
+    Copy,
+    /// Re-encode, with the reason why the input can't be kept
+    Encode(String),
+    /// Leave the stream out of the output
+    Drop,
+    /// The input has no such stream
+    Missing,
+}
+
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub video: StreamAction,
+    pub audio: StreamAction,
+}
+
+impl Plan {
+    pub fn is_remux(&self) -> bool {
+        !matches!(self.video, StreamAction::Encode(_))
+            && !matches!(self.audio, StreamAction::Encode(_))
+    }
+}
+
+/// H.264 profiles recent Telegram clients decode in hardware. Each preset
+/// narrows this to its own profile: `mobile-safe` keeps baseline for old
+/// phones, so it re-encodes the main and high streams most cameras and
+/// recorders write.
+const VIDEO_PROFILES: &[&str] = &["Baseline", "Constrained Baseline", "Main", "High"];
+
+pub fn plan(media: &MediaInfo, args: &Args, preset: &Preset) -> Plan {
+    let forced = || StreamAction::Encode("--force-encode given".to_string());
+
+    let video = match media.video() {
+        None => StreamAction::Missing,
+        Some(_) if args.force_encode => forced(),
+        // A copy can only start at a keyframe
+        Some(_) if args.start.is_some() => {
+            StreamAction::Encode("--start needs a frame accurate cut".to_string())
+        }
+        Some(v) => match (args.target_size, media.size) {
+            // A copy can't shrink the file, so anything over the target is re-encoded
+            (Some(target_size), Some(size)) if size > target_size => StreamAction::Encode(format!(
+                "{} is over the {} target",
+                format_bytes(size),
+                format_bytes(target_size)
+            )),
+            _ => match rotate::needs_encode(v, args) {
+                Some(reason) => StreamAction::Encode(reason),
+                None => video_action(v, args, preset),
+            },
+        },
+    };
+
+    let audio = match (media.audio(), &preset.audio) {
+        (None, _) => StreamAction::Missing,
+        (Some(_), None) => StreamAction::Drop,
+        (Some(_), Some(_)) if args.force_encode => forced(),
+        (Some(a), Some(_)) => audio_action(a),
+    };
+
+    Plan { video, audio }
+}
+
+fn video_action(video: &VideoStream, args: &Args, preset: &Preset) -> StreamAction {
+    if preset.container != Container::Mp4 {
+        return StreamAction::Encode(format!(
+            "the {} preset always encodes with {}",
+            preset.name,
+            preset.video_codec.encoder()
+        ));
+    }
+    if video.codec_name != preset.video_codec.stream_name() {
+        return StreamAction::Encode(format!(
+            "{} is not {}",
+            video.codec_name,
+            preset.video_codec.label()
+        ));
+    }
+    match video.pix_fmt.as_deref() {
+        Some("yuv420p") => {}
+        Some(other) => return StreamAction::Encode(format!("pixel format is {}", other)),
+        None => return StreamAction::Encode("unknown pixel format".to_string()),
+    }
+    if let Some(profile) = &video.profile
+        && preset.video_codec == VideoCodec::H264
+        && !VIDEO_PROFILES.contains(&profile.as_str())
+    {
+        return StreamAction::Encode(format!("H.264 profile {} is not widely supported", profile));
+    }
+    if let (Some(profile), Some(max_profile)) = (&video.profile, &preset.profile)
+        && let (Some(rank), Some(max_rank)) =
+            (h264::profile_rank(profile), h264::profile_rank(max_profile))
+        && rank > max_rank
+    {
+        return StreamAction::Encode(format!(
+            "H.264 profile {} is above the {} preset's {}",
+            profile, preset.name, max_profile
+        ));
+    }
+    if let (Some(level), Some(max_level)) = (video.level, preset.max_level)
+        && level > max_level
+    {
+        return StreamAction::Encode(format!(
+            "H.264 level {} is above the {} preset's {}",
+            h264::format_level(level),
+            preset.name,
+            h264::format_level(max_level)
+        ));
+    }
+    if let Some(bit_rate) = video.bit_rate
+        && bit_rate / 1000 > u64::from(preset.bitrate)
+    {
+        return StreamAction::Encode(format!(
+            "{}kbps exceeds the {}kbps limit",
+            bit_rate / 1000,
+            preset.bitrate
+        ));
+    }
+    let (width, height) = rotate::frame_size(video, args);
+    if width > preset.max_width.unwrap_or(u32::MAX)
+        || height > preset.max_height.unwrap_or(u32::MAX)
+        || width.max(height) > preset.max_dimension.unwrap_or(u32::MAX)
+    {
+        return StreamAction::Encode(format!(
+            "{}x{} is larger than the {} preset allows",
+            width, height, preset.name
+        ));
+    }
+    if preset.square {
+        return StreamAction::Encode(format!(
+            "the {} preset crops the video to a square",
+            preset.name
+        ));
+    }
+    if let Some(reason) = framerate::needs_encode(video, preset) {
+        return StreamAction::Encode(reason);
+    }
+    StreamAction::Copy
+}
+
+fn audio_action(audio: &AudioStream) -> StreamAction {
+    if audio.codec_name != "aac" {
+        return StreamAction::Encode(format!("{} is not AAC", audio.codec_name));
+    }
+    if let Some(profile) = &audio.profile
+        && profile != "LC"
+    {
+        return StreamAction::Encode(format!("AAC profile {} is not AAC-LC", profile));
+    }
+    if audio.channels > 2 {
+        return StreamAction::Encode(format!("{} channels need a stereo downmix", audio.channels));
+    }
+    StreamAction::Copy
+}
+
+impl fmt::Display for StreamAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamAction::Copy => write!(f, "copy (already compatible)"),
+            StreamAction::Encode(reason) => write!(f, "re-encode ({})", reason),
+            StreamAction::Drop => write!(f, "drop"),
+            StreamAction::Missing => write!(f, "none"),
+        }
+    }
+}
@@ -0,0 +1,395 @@
+//! User configuration file with defaults and named profiles.
+//!
+//! Settings are merged with the precedence CLI > profile > config > preset.
+
+use crate::codec::{Speed, Tune, VideoCodec};
+use crate::framerate::FpsPolicy;
+use crate::preset;
+use crate::rate::RateMode;
+use crate::rotate::RotationPolicy;
+use crate::screen::Content;
+use crate::timestamp::{self, Timestamp};
+use crate::toml::{self, Table, Value};
+use crate::{Args, parse_bytes};
+use clap::ValueEnum;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+pub struct Config {
+    /// Where the config was looked for
+    path: PathBuf,
+    defaults: Table,
+    profiles: Table,
+}
+
+pub fn default_path() -> PathBuf {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .unwrap_or_else(|| {
+            let home = std::env::var_os("HOME").unwrap_or_default();
+            PathBuf::from(home).join(".config")
+        });
+    base.join("telegram-video-converter").join("config.toml")
+}
+
+impl Config {
+    /// Read the config file; a missing file is the same as an empty one.
+    pub fn load(path: Option<PathBuf>) -> Result<Config, String> {
+        let explicit = path.is_some();
+        let path = path.unwrap_or_else(default_path);
+
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => Some(text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => None,
+            Err(e) => return Err(format!("Cannot read config '{}': {}", path.display(), e)),
+        };
+
+        let mut defaults = match &text {
+            Some(text) => toml::parse(text)
+                .map_err(|e| format!("Invalid config '{}': {}", path.display(), e))?,
+            None => Table::new(),
+        };
+        let profiles = match defaults.remove("profiles") {
+            Some(Value::Table(profiles)) => profiles,
+            Some(_) => {
+                return Err(format!(
+                    "'profiles' in '{}' must be a table",
+                    path.display()
+                ));
+            }
+            None => Table::new(),
+        };
+
+        Ok(Config {
+            path,
+            defaults,
+            profiles,
+        })
+    }
+
+    /// Fill every setting not given on the command line, first from the
+    /// selected profile and then from the top level of the config. A key the
+    /// profile sets is settled there, even to `false` or `[]`.
+    pub fn apply(&self, args: &mut Args) -> Result<(), String> {
+        // Mode flags on the command line stand for a preset, which a preset
+        // or mode from the config mustn't override
+        if let Some(name) = preset::mode_preset(args) {
+            args.preset = Some(name.to_string());
+        }
+
+        let mut seen = BTreeSet::new();
+        if let Some(name) = args.profile.clone() {
+            let profile = match self.profiles.get(&name) {
+                Some(Value::Table(profile)) => profile,
+                Some(_) => return Err(format!("profile '{}' must be a table", name)),
+                None => {
+                    let known: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+                    return Err(format!(
+                        "unknown profile '{}' (defined: {})",
+                        name,
+                        if known.is_empty() {
+                            "none".to_string()
+                        } else {
+                            known.join(", ")
+                        }
+                    ));
+                }
+            };
+            fill(args, profile, &mut seen).map_err(|e| format!("profile '{}': {}", name, e))?;
+        }
+
+        fill(args, &self.defaults, &mut seen).map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+}
+
+/// Fill `args` from one layer of the config, skipping the keys in `seen`
+/// that a higher layer already set. The keys that fill a setting are
+/// recorded in `args.config_keys`.
+fn fill(args: &mut Args, table: &Table, seen: &mut BTreeSet<String>) -> Result<(), String> {
+    for (key, value) in table {
+        if !seen.insert(key.clone()) {
+            continue;
+        }
+        let filled = match key.as_str() {
+            "include" => fill_list(&mut args.include, key, value)?,
+            "exclude" => fill_list(&mut args.exclude, key, value)?,
+            "preset" => fill_option(&mut args.preset, key, value, string)?,
+            "codec" => fill_option(&mut args.codec, key, value, codec)?,
+            "rate_control" => fill_option(&mut args.rate_control, key, value, rate_control)?,
+            "bitrate" => fill_option(&mut args.bitrate, key, value, number)?,
+            "audio_bitrate" => fill_option(&mut args.audio_bitrate, key, value, number)?,
+            "fps" => fill_option(&mut args.fps, key, value, number)?,
+            "fps_policy" => fill_option(&mut args.fps_policy, key, value, fps_policy)?,
+            "crf" => fill_option(&mut args.crf, key, value, number)?,
+            "qp" => fill_option(&mut args.qp, key, value, number)?,
+            "speed" => fill_option(&mut args.speed, key, value, speed)?,
+            "tune" => fill_option(&mut args.tune, key, value, tune)?,
+            "content" => fill_option(&mut args.content, key, value, content)?,
+            "max_width" => fill_option(&mut args.max_width, key, value, number)?,
+            "max_height" => fill_option(&mut args.max_height, key, value, number)?,
+            "max_dimension" => fill_option(&mut args.max_dimension, key, value, number)?,
+            "no_upscale" => fill_flag(&mut args.no_upscale, key, value)?,
+            "rotation" => fill_option(&mut args.rotation, key, value, rotation)?,
+            "target_size" => fill_option(&mut args.target_size, key, value, size)?,
+            "split_size" => fill_option(&mut args.split_size, key, value, size)?,
+            "split_duration" => fill_option(&mut args.split_duration, key, value, time)?,
+            "video_note" | "sticker" | "animation" => fill_mode(&mut args.preset, key, value)?,
+            "trim_to_limit" => fill_flag(&mut args.trim_to_limit, key, value)?,
+            "thumbnail" => fill_flag(&mut args.thumbnail, key, value)?,
+            "thumbnail_at" => fill_option(&mut args.thumbnail_at, key, value, time)?,
+            "embed_thumbnail" => fill_flag(&mut args.embed_thumbnail, key, value)?,
+            "send" => fill_flag(&mut args.send, key, value)?,
+            "bot_token" => fill_option(&mut args.bot_token, key, value, string)?,
+            "chat_id" => fill_option(&mut args.chat_id, key, value, chat_id)?,
+            "caption" => fill_option(&mut args.caption, key, value, string)?,
+            "api_url" => fill_option(&mut args.api_url, key, value, string)?,
+            "overwrite" => fill_flag(&mut args.overwrite, key, value)?,
+            "two_pass" => fill_flag(&mut args.two_pass, key, value)?,
+            "force_encode" => fill_flag(&mut args.force_encode, key, value)?,
+            "verbose" => fill_flag(&mut args.verbose, key, value)?,
+            "threads" => fill_option(&mut args.threads, key, value, |key, value| {
+                number(key, value).map(|n| n as usize)
+            })?,
+            "nice" => fill_flag(&mut args.nice, key, value)?,
+            _ => return Err(format!("unknown setting '{}'", key)),
+        };
+        if filled {
+            args.config_keys.insert(key.clone());
+        }
+    }
+    Ok(())
+}
+
+fn type_error(key: &str, expected: &str, value: &Value) -> String {
+    format!("'{}' must be {}, not {}", key, expected, value.type_name())
+}
+
+fn fill_option<T>(
+    field: &mut Option<T>,
+    key: &str,
+    value: &Value,
+    convert: fn(&str, &Value) -> Result<T, String>,
+) -> Result<bool, String> {
+    if field.is_some() {
+        return Ok(false);
+    }
+    *field = Some(convert(key, value)?);
+    Ok(true)
+}
+
+/// Flags can only be switched on from the config, as the command line has
+/// no way to switch them off again.
+fn fill_flag(field: &mut bool, key: &str, value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(b) => {
+            let filled = *b && !*field;
+            *field |= b;
+            Ok(filled)
+        }
+        _ => Err(type_error(key, "a boolean", value)),
+    }
+}
+
+/// Mode keys select their preset, unless a preset is already set.
+fn fill_mode(preset: &mut Option<String>, key: &str, value: &Value) -> Result<bool, String> {
+    let mut on = false;
+    fill_flag(&mut on, key, value)?;
+    if !on || preset.is_some() {
+        return Ok(false);
+    }
+    *preset = Some(key.replace('_', "-"));
+    Ok(true)
+}
+
+fn fill_list(field: &mut Vec<String>, key: &str, value: &Value) -> Result<bool, String> {
+    let Value::Array(items) = value else {
+        return Err(type_error(key, "an array", value));
+    };
+    if !field.is_empty() {
+        return Ok(false);
+    }
+    *field = items
+        .iter()
+        .map(|item| string(key, item))
+        .collect::<Result<_, _>>()?;
+    Ok(true)
+}
+
+fn string(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        _ => Err(type_error(key, "a string", value)),
+    }
+}
+
+/// Chat ids are numbers, or `@channelname` for public channels.
+fn chat_id(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::String(s) => Ok(s.clone()),
+        _ => Err(type_error(key, "an integer or a string", value)),
+    }
+}
+
+fn number(key: &str, value: &Value) -> Result<u32, String> {
+    match value {
+        Value::Integer(i) => {
+            u32::try_from(*i).map_err(|_| format!("'{}' is out of range: {}", key, i))
+        }
+        _ => Err(type_error(key, "an integer", value)),
+    }
+}
+
+fn rate_control(key: &str, value: &Value) -> Result<RateMode, String> {
+    let name = string(key, value)?;
+    RateMode::from_str(&name, false).map_err(|_| {
+        format!(
+            "'{}' must be \"crf\", \"capped-crf\", \"abr\", \"cbr\" or \"qp\", not {:?}",
+            key, name
+        )
+    })
+}
+
+fn speed(key: &str, value: &Value) -> Result<Speed, String> {
+    let name = string(key, value)?;
+    Speed::from_str(&name, false)
+        .map_err(|_| format!("'{}' must be ultrafast to veryslow, not {:?}", key, name))
+}
+
+fn tune(key: &str, value: &Value) -> Result<Tune, String> {
+    let name = string(key, value)?;
+    Tune::from_str(&name, false).map_err(|_| {
+        format!(
+            "'{}' must be \"film\", \"animation\", \"stillimage\" or \"grain\", not {:?}",
+            key, name
+        )
+    })
+}
+
+fn content(key: &str, value: &Value) -> Result<Content, String> {
+    let name = string(key, value)?;
+    Content::from_str(&name, false).map_err(|_| {
+        format!(
+            "'{}' must be \"auto\", \"screen\" or \"other\", not {:?}",
+            key, name
+        )
+    })
+}
+
+fn fps_policy(key: &str, value: &Value) -> Result<FpsPolicy, String> {
+    let name = string(key, value)?;
+    FpsPolicy::from_str(&name, false).map_err(|_| {
+        format!(
+            "'{}' must be \"keep\", \"cap\" or \"force\", not {:?}",
+            key, name
+        )
+    })
+}
+
+fn rotation(key: &str, value: &Value) -> Result<RotationPolicy, String> {
+    let name = string(key, value)?;
+    RotationPolicy::from_str(&name, false)
+        .map_err(|_| format!("'{}' must be \"bake\" or \"metadata\", not {:?}", key, name))
+}
+
+fn codec(key: &str, value: &Value) -> Result<VideoCodec, String> {
+    let name = string(key, value)?;
+    VideoCodec::from_str(&name, false).map_err(|_| {
+        format!(
+            "'{}' must be \"h264\", \"h265\", \"vp9\", \"av1\" or \"av1-aom\", not {:?}",
+            key, name
+        )
+    })
+}
+
+/// Sizes are written either as bytes or like on the command line (`"50MB"`).
+/// Times are strings such as `"10m"`, or a number of seconds.
+fn time(key: &str, value: &Value) -> Result<Timestamp, String> {
+    let seconds = match value {
+        Value::Integer(i) => *i as f64,
+        Value::Float(f) => *f,
+        Value::String(s) => return timestamp::parse(s).map_err(|e| format!("'{}': {}", key, e)),
+        _ => return Err(type_error(key, "a time", value)),
+    };
+    if seconds < 0.0 {
+        return Err(format!("'{}' must not be negative: {}", key, seconds));
+    }
+    Ok(Timestamp::Seconds(seconds))
+}
+
+fn size(key: &str, value: &Value) -> Result<u64, String> {
+    match value {
+        Value::Integer(i) => {
+            u64::try_from(*i).map_err(|_| format!("'{}' is out of range: {}", key, i))
+        }
+        Value::String(s) => parse_bytes(s).map_err(|e| format!("'{}': {}", key, e)),
+        _ => Err(type_error(key, "a size", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn config(text: &str) -> Config {
+        let mut defaults = toml::parse(text).unwrap();
+        let profiles = match defaults.remove("profiles") {
+            Some(Value::Table(profiles)) => profiles,
+            _ => Table::new(),
+        };
+        Config {
+            path: PathBuf::from("config.toml"),
+            defaults,
+            profiles,
+        }
+    }
+
+    fn apply(text: &str, argv: &[&str]) -> Args {
+        let argv = ["telegram-video-converter", "in.mp4"].iter().chain(argv);
+        let mut args = crate::Cli::try_parse_from(argv).unwrap().args;
+        config(text).apply(&mut args).unwrap();
+        args
+    }
+
+    const LAYERED: &str = r#"
+verbose = true
+exclude = ["mkv"]
+crf = 30
+
+[profiles.quiet]
+verbose = false
+exclude = []
+"#;
+
+    #[test]
+    fn fills_from_the_top_level() {
+        let args = apply(LAYERED, &[]);
+        assert!(args.verbose);
+        assert_eq!(args.exclude, ["mkv"]);
+        assert_eq!(args.crf, Some(30));
+    }
+
+    #[test]
+    fn profile_overrides_the_top_level() {
+        let args = apply(LAYERED, &["--profile", "quiet"]);
+        assert!(!args.verbose);
+        assert!(args.exclude.is_empty());
+        assert_eq!(args.crf, Some(30));
+    }
+
+    #[test]
+    fn command_line_overrides_the_profile() {
+        let args = apply(LAYERED, &["--profile", "quiet", "--verbose", "--crf", "20"]);
+        assert!(args.verbose);
+        assert_eq!(args.crf, Some(20));
+    }
+
+    #[test]
+    fn profile_mode_is_not_replaced_by_the_top_level() {
+        let text = "sticker = true\n[profiles.plain]\nsticker = false\n";
+        assert_eq!(apply(text, &[]).preset.as_deref(), Some("sticker"));
+        assert_eq!(apply(text, &["--profile", "plain"]).preset, None);
+    }
+}
@@ -0,0 +1,534 @@
+//! Build and run the ffmpeg command line for a conversion.
+
+use crate::Args;
+use crate::compat::{Plan, StreamAction};
+use crate::framerate::FrameRate;
+use crate::h264;
+use crate::preset::{Container, Preset};
+use crate::progress::Progress;
+use crate::rate::RateControl;
+use crate::rotate;
+use crate::scheduler::Job;
+use crate::tempdir::TempDir;
+use std::path::Path;
+use std::process::Command;
+
+/// Share of the target size kept free for the MP4 container overhead.
+const CONTAINER_OVERHEAD: f64 = 0.02;
+
+/// Lowest video bitrate (kbps) still worth encoding at.
+const MIN_VIDEO_BITRATE: u32 = 100;
+
+/// Video bitrate in kbps that fits `duration` seconds of video and audio
+/// into `target_size` bytes.
+pub fn bitrate_for_size(
+    target_size: u64,
+    duration: f64,
+    audio_bitrate: u32,
+) -> Result<u32, String> {
+    if duration <= 0.0 {
+        return Err("input duration is zero".to_string());
+    }
+
+    let total_kbps = target_size as f64 * 8.0 * (1.0 - CONTAINER_OVERHEAD) / duration / 1000.0;
+    let video_kbps = total_kbps - f64::from(audio_bitrate);
+    if video_kbps < f64::from(MIN_VIDEO_BITRATE) {
+        return Err(format!(
+            "only {:.0}kbps left for video over {:.1}s",
+            video_kbps.max(0.0),
+            duration
+        ));
+    }
+
+    Ok(video_kbps as u32)
+}
+
+/// One conversion of `input` into `output`.
+pub struct Encode<'a> {
+    pub input: &'a str,
+    pub output: &'a str,
+    pub args: &'a Args,
+    pub plan: &'a Plan,
+    pub preset: &'a Preset,
+    /// Decoder to read the input video with instead of ffmpeg's default
+    pub decoder: Option<&'a str>,
+    /// Extra times the input is played
+    pub loop_count: u32,
+    /// Seconds of the input to skip
+    pub start: Option<f64>,
+    /// Seconds between forced keyframes, so the output can be cut there
+    pub keyframe_interval: Option<f64>,
+    /// Rotation in degrees to carry over as metadata instead of applying it
+    pub keep_rotation: Option<u32>,
+    /// H.264 level written to the stream, multiplied by ten
+    pub level: Option<u32>,
+    pub rate: RateControl,
+    /// Run an analysis pass first (needs an average bitrate)
+    pub two_pass: bool,
+    /// Constant output frame rate; `None` keeps the source's frame timing
+    pub frame_rate: Option<FrameRate>,
+    /// Cut the output after this many seconds
+    pub trim_to: Option<f64>,
+    /// Duration of the output in seconds, used to show progress
+    pub duration: Option<f64>,
+    pub job: &'a Job<'a>,
+}
+
+/// Which pass of a two-pass encode a command is for.
+#[derive(Clone, Copy)]
+enum Pass<'a> {
+    Single,
+    Analysis(&'a Path),
+    Final(&'a Path),
+}
+
+impl Encode<'_> {
+    /// Run the conversion, with an analysis pass first in two-pass mode.
+    pub fn run(&self) -> Result<(), String> {
+        let codec = self.preset.video_codec;
+        if self.two_pass && !codec.supports_two_pass() {
+            self.job.say(format!(
+                "{} has no two-pass mode, encoding in one pass",
+                codec.encoder()
+            ));
+        }
+        if !self.two_pass
+            || !codec.supports_two_pass()
+            || !matches!(self.plan.video, StreamAction::Encode(_))
+        {
+            let label = if self.plan.is_remux() {
+                "Remuxing"
+            } else {
+                "Encoding"
+            };
+            return self.run_ffmpeg(self.command(Pass::Single), label);
+        }
+
+        // The pass logs live in a private directory that goes away with
+        // `log_dir`, whether or not the passes succeed
+        let log_dir = TempDir::new("passlog")
+            .map_err(|e| format!("Failed to create pass log directory: {}", e))?;
+        let log_file = log_dir.path().join("ffmpeg2pass");
+
+        self.run_ffmpeg(self.command(Pass::Analysis(&log_file)), "Pass 1/2")?;
+        self.run_ffmpeg(self.command(Pass::Final(&log_file)), "Pass 2/2")
+    }
+
+    fn run_ffmpeg(&self, cmd: Command, label: &str) -> Result<(), String> {
+        run_ffmpeg(cmd, label, self.args.verbose, self.duration, self.job)
+    }
+
+    fn command(&self, pass: Pass) -> Command {
+        let args = self.args;
+        let plan = self.plan;
+        let preset = self.preset;
+        let mut cmd = Command::new("ffmpeg");
+
+        // Machine-readable progress on stdout replaces the stats line
+        if !args.verbose {
+            cmd.args(["-progress", "pipe:1", "-nostats"]);
+        }
+
+        // Input file
+        // ffmpeg turns the pixels upright by itself unless told not to
+        if self.keep_rotation.is_some() {
+            cmd.arg("-noautorotate");
+        }
+        if self.loop_count > 0 {
+            cmd.args(["-stream_loop", &self.loop_count.to_string()]);
+        }
+        // Seeking on the input is frame accurate when the video is encoded
+        if let Some(start) = self.start {
+            cmd.args(["-ss", &start.to_string()]);
+        }
+        if let Some(decoder) = self.decoder {
+            cmd.args(["-c:v", decoder]);
+        }
+        cmd.args(["-i", self.input]);
+
+        // Overwrite flag
+        if args.overwrite {
+            cmd.arg("-y");
+        }
+
+        // Only keep the streams the plan was made for
+        if plan.video != StreamAction::Missing {
+            cmd.args(["-map", "0:v:0"]);
+        }
+        if matches!(plan.audio, StreamAction::Copy | StreamAction::Encode(_))
+            && !matches!(pass, Pass::Analysis(_))
+        {
+            cmd.args(["-map", "0:a:0"]);
+        }
+
+        match plan.video {
+            StreamAction::Copy => {
+                cmd.args(["-c:v", "copy"]);
+            }
+            StreamAction::Encode(_) => {
+                // Video encoding settings
+                let codec = preset.video_codec;
+                cmd.args(["-c:v", codec.encoder()]);
+                if let Some(profile) = &preset.profile {
+                    cmd.args(["-profile:v", profile]);
+                }
+                if let Some(level) = self.level {
+                    cmd.args(["-level", &h264::format_level(level)]);
+                }
+                cmd.args(["-pix_fmt", &preset.pix_fmt]);
+                cmd.args(codec.speed_args(preset.speed));
+                if let Some(tune) = preset.tune {
+                    cmd.args(codec.tune_args(tune));
+                }
+                cmd.args(self.rate.args(codec));
+                // Uncapped CRF still has to stay within the bitrate of the
+                // level it declares
+                if let RateControl::Crf(_) = self.rate
+                    && let Some(level) = self.level
+                    && let Some((max_bitrate, buffer_size)) =
+                        h264::max_rates(preset.profile.as_deref(), level)
+                {
+                    cmd.args(["-maxrate", &format!("{}k", max_bitrate)]);
+                    cmd.args(["-bufsize", &format!("{}k", buffer_size)]);
+                }
+                if let Some(interval) = self.keyframe_interval {
+                    cmd.args([
+                        "-force_key_frames",
+                        &format!("expr:gte(t,n_forced*{})", interval),
+                    ]);
+                }
+                // Dropped duplicates leave gaps that a fixed rate would fill again
+                if preset.decimate {
+                    cmd.args(["-fps_mode", "vfr"]);
+                } else if let Some(rate) = self.frame_rate {
+                    cmd.args(["-r", &rate.to_arg()]);
+                } else {
+                    cmd.args(["-fps_mode", "passthrough"]);
+                }
+                cmd.args(["-vf", &video_filters(args, preset, self.frame_rate)]);
+                if let Some(rotation) = self.keep_rotation {
+                    cmd.args(["-metadata:s:v:0", &format!("rotate={}", rotation)]);
+                }
+                if let Some(threads) = args.threads.or(self.job.threads) {
+                    cmd.args(["-threads", &threads.to_string()]);
+                }
+
+                match pass {
+                    Pass::Single => {}
+                    Pass::Analysis(log_file) => {
+                        cmd.args(codec.pass_args(1, log_file));
+                    }
+                    Pass::Final(log_file) => {
+                        cmd.args(codec.pass_args(2, log_file));
+                    }
+                }
+            }
+            StreamAction::Drop | StreamAction::Missing => {}
+        }
+        // Copied streams are in the preset's format too
+        if let Some(tag) = preset.video_codec.tag()
+            && matches!(plan.video, StreamAction::Copy | StreamAction::Encode(_))
+        {
+            cmd.args(["-tag:v", tag]);
+        }
+
+        if let Some(trim_to) = self.trim_to {
+            cmd.args(["-t", &trim_to.to_string()]);
+        }
+
+        // The analysis pass only needs the video, and its output is discarded
+        if let Pass::Analysis(_) = pass {
+            cmd.args(["-an", "-f", "null", "-"]);
+            if !args.verbose {
+                cmd.args(["-loglevel", "error"]);
+            }
+            return cmd;
+        }
+
+        match (&plan.audio, &preset.audio) {
+            (StreamAction::Copy, _) => {
+                cmd.args(["-c:a", "copy"]);
+            }
+            (StreamAction::Encode(_), Some(audio)) => {
+                // Audio encoding settings
+                cmd.args([
+                    "-c:a",
+                    &audio.codec,
+                    "-ar",
+                    &audio.sample_rate.to_string(),
+                    "-ac",
+                    &audio.channels.to_string(),
+                    "-b:a",
+                    &format!("{}k", audio.bitrate),
+                ]);
+            }
+            (StreamAction::Drop, _) => {
+                cmd.arg("-an");
+            }
+            _ => {}
+        }
+
+        // Output format and optimizations
+        if preset.container == Container::Mp4 {
+            cmd.args(["-movflags", "+faststart"]);
+        }
+        cmd.args(["-f", preset.container.format(), self.output]);
+
+        // Hide ffmpeg output unless verbose
+        if !args.verbose {
+            cmd.args(["-loglevel", "error"]);
+        }
+
+        cmd
+    }
+}
+
+/// Run one ffmpeg command, showing progress through `duration` seconds
+/// of output unless ffmpeg's own output is shown.
+pub fn run_ffmpeg(
+    mut cmd: Command,
+    label: &str,
+    verbose: bool,
+    duration: Option<f64>,
+    job: &Job,
+) -> Result<(), String> {
+    // In verbose mode ffmpeg reports its own progress
+    let status = if verbose {
+        job.say(format!("  {}", label));
+        job.run_shown(&mut cmd)
+    } else {
+        Progress::new(label, duration, job).run(&mut cmd)
+    };
+
+    match status {
+        Ok(exit_status) if exit_status.success() => Ok(()),
+        Ok(_) if job.is_cancelled() => Err("Cancelled after another job failed".to_string()),
+        Ok(exit_status) => Err(format!(
+            "Conversion failed with exit code: {:?}",
+            exit_status.code()
+        )),
+        Err(e) => Err(format!("Failed to execute ffmpeg: {}", e)),
+    }
+}
+
+/// Manual rotation and flips, then the preset's square crop, followed by
+/// scaling to its size limits and dropping repeated frames.
+fn video_filters(args: &Args, preset: &Preset, frame_rate: Option<FrameRate>) -> String {
+    let mut filters = rotate::filters(args);
+    if preset.square {
+        filters.push("crop='min(iw,ih)':'min(iw,ih)'".to_string());
+        filters.push("setsar=1".to_string());
+    }
+    filters.push(scale_filter(preset));
+    if preset.decimate {
+        // The rate is capped first; at least one frame a second is kept so
+        // players can still seek through long still stretches
+        if let Some(rate) = frame_rate {
+            filters.push(format!("fps={}", rate.to_arg()));
+        }
+        let max = frame_rate.map_or(preset.fps, FrameRate::ceil);
+        filters.push(format!("mpdecimate=max={}", max));
+    }
+    filters.join(",")
+}
+
+/// Width and height limits, with the longest side limit folded into both.
+fn size_limits(preset: &Preset) -> (Option<u32>, Option<u32>) {
+    let limit = |max: Option<u32>| match (max, preset.max_dimension) {
+        (Some(max), Some(dimension)) => Some(max.min(dimension)),
+        (max, dimension) => max.or(dimension),
+    };
+    (limit(preset.max_width), limit(preset.max_height))
+}
+
+/// Size of a `width`x`height` video after the preset's crop and scaling.
+pub fn output_size(preset: &Preset, width: u32, height: u32) -> (u32, u32) {
+    if preset.square {
+        let side = width.min(height);
+        scaled_size(preset, side, side)
+    } else {
+        scaled_size(preset, width, height)
+    }
+}
+
+/// Most macroblocks per frame the preset's highest H.264 level decodes, so
+/// a longest side limit still keeps square and portrait frames in the level.
+fn max_macroblocks(preset: &Preset) -> Option<u64> {
+    preset
+        .max_level
+        .filter(|_| preset.fit_level)
+        .and_then(h264::max_frame_macroblocks)
+}
+
+/// Widest even width a `width`x`height` frame scales to within
+/// `macroblocks`: the width of a frame of that many macroblocks' pixels,
+/// narrowed until the 16 pixel macroblocks it starts in fit as well.
+/// `level_width_expr` computes the same in ffmpeg.
+fn level_width(macroblocks: u64, width: u32, height: u32) -> f64 {
+    let (width, height) = (f64::from(width.max(1)), f64::from(height.max(1)));
+    let fits =
+        |w: f64| (w / 16.0).ceil() * (w * height / width / 16.0).ceil() <= macroblocks as f64;
+    let mut w = (macroblocks as f64 * 256.0 * width / height).sqrt().floor();
+    while w > 2.0 && !fits(w) {
+        w -= 2.0;
+    }
+    w
+}
+
+/// `level_width` as an ffmpeg expression over the scaler's input size.
+fn level_width_expr(macroblocks: u64) -> String {
+    format!(
+        "st(0,floor(sqrt({}*iw/ih)));\
+         while(gt(ld(0),2)*gt(ceil(ld(0)/16)*ceil(ld(0)*ih/iw/16),{}),st(0,ld(0)-2));\
+         ld(0)",
+        macroblocks * 256,
+        macroblocks
+    )
+}
+
+/// Size of a `width`x`height` video after `scale_filter`.
+fn scaled_size(preset: &Preset, width: u32, height: u32) -> (u32, u32) {
+    let (max_width, max_height) = size_limits(preset);
+    // Scale factor each limit allows; a missing one doesn't constrain
+    let ratio = |max: Option<u32>, own: u32| match max {
+        Some(max) if preset.upscale => f64::from(max) / f64::from(own.max(1)),
+        Some(max) => f64::from(max.min(own)) / f64::from(own.max(1)),
+        None => f64::INFINITY,
+    };
+    let level_ratio = max_macroblocks(preset).map_or(f64::INFINITY, |max| {
+        level_width(max, width, height) / f64::from(width.max(1))
+    });
+
+    // Shrink to whichever limit is hit first, then round down to even
+    let scale = ratio(max_width, width)
+        .min(ratio(max_height, height))
+        .min(level_ratio);
+    let scale = if scale.is_finite() { scale } else { 1.0 };
+    let scale = if preset.upscale {
+        scale
+    } else {
+        scale.min(1.0)
+    };
+    let even = |size: f64| (size as u32 / 2 * 2).max(2);
+    (
+        even(f64::from(width) * scale),
+        even(f64::from(height) * scale),
+    )
+}
+
+/// Fit the video into the preset's size limits keeping its aspect ratio.
+/// The scale is applied even without limits, as `yuv420p` needs even
+/// dimensions and not every input has them.
+fn scale_filter(preset: &Preset) -> String {
+    let (max_width, max_height) = size_limits(preset);
+    let max_macroblocks = max_macroblocks(preset);
+    let size = |max: Option<u32>, own: &str| {
+        let mut bounds: Vec<String> = max.iter().map(u32::to_string).collect();
+        if !preset.upscale {
+            bounds.push(own.to_string());
+        }
+        // The side of the widest frame in the level with the input's
+        // aspect ratio
+        if let Some(macroblocks) = max_macroblocks {
+            let width = level_width_expr(macroblocks);
+            bounds.push(match own {
+                "iw" => format!("({})", width),
+                _ => format!("({})*ih/iw", width),
+            });
+        }
+        match bounds.split_first() {
+            None => "-1".to_string(),
+            Some((only, [])) if !only.contains('(') => only.clone(),
+            Some((first, rest)) => {
+                let min = rest.iter().fold(first.clone(), |min, bound| {
+                    format!("min({},{})", min, bound)
+                });
+                format!("'{}'", min)
+            }
+        }
+    };
+    format!(
+        "scale=w={}:h={}:force_original_aspect_ratio=decrease:force_divisible_by=2",
+        size(max_width, "iw"),
+        size(max_height, "ih")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset;
+    use clap::Parser;
+
+    /// Level of a `width`x`height` frame alone, without rate limits.
+    fn frame_level(preset: &Preset, width: u32, height: u32) -> Option<u32> {
+        let stream = h264::Stream {
+            width,
+            height,
+            fps: 1,
+            max_bitrate: 0,
+            buffer_size: 0,
+        };
+        h264::level_for(preset.profile.as_deref(), &stream)
+    }
+
+    #[test]
+    fn keeps_frames_in_the_level() {
+        let sizes = [
+            (1440, 1080),
+            (1920, 1440),
+            (1280, 1024),
+            (1080, 1440),
+            (2000, 2000),
+        ];
+        for name in preset::BUILTIN_NAMES {
+            let preset = preset::builtin(name).unwrap();
+            let Some(max_level) = preset.max_level else {
+                continue;
+            };
+            for (width, height) in sizes {
+                let (w, h) = scaled_size(&preset, width, height);
+                let level = frame_level(&preset, w, h);
+                assert!(
+                    level.is_some_and(|level| level <= max_level),
+                    "{}: {}x{} scaled to {}x{} needs level {:?}",
+                    name,
+                    width,
+                    height,
+                    w,
+                    h,
+                    level
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fills_the_level() {
+        let preset = preset::builtin("mobile-safe").unwrap();
+        // 3600 macroblocks at level 3.1: 1280x720 is exactly 80x45
+        assert_eq!(scaled_size(&preset, 1920, 1080), (1280, 720));
+        assert_eq!(scaled_size(&preset, 1440, 1080), (1104, 828));
+    }
+
+    #[test]
+    fn keeps_the_users_own_limits() {
+        let argv = [
+            "telegram-video-converter",
+            "in.mp4",
+            "--max-dimension",
+            "1920",
+        ];
+        let args = crate::Cli::try_parse_from(argv).unwrap().args;
+        let preset = preset::resolve(&args).unwrap();
+        assert_eq!(scaled_size(&preset, 1920, 1080), (1920, 1080));
+    }
+
+    #[test]
+    fn writes_the_level_bound_for_ffmpeg() {
+        let preset = preset::builtin("mobile-safe").unwrap();
+        let filter = scale_filter(&preset);
+        assert!(filter.contains("sqrt(921600*iw/ih)"), "{}", filter);
+        assert!(filter.contains(",3600),st(0,ld(0)-2))"), "{}", filter);
+    }
+}
@@ -0,0 +1,350 @@
+//! Pick the output frame rate from the source's rate and the preset's
+//! frame rate policy.
+
+use crate::preset::Preset;
+use crate::probe::VideoStream;
+use std::fmt;
+
+/// What to do with the source's frame rate, as chosen with `--fps-policy`.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum FpsPolicy {
+    /// Keep the source's frame rate
+    Keep,
+    /// Keep the source's frame rate up to --fps, above it divide it down
+    Cap,
+    /// Always use --fps
+    Force,
+}
+
+impl FpsPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            FpsPolicy::Keep => "keep",
+            FpsPolicy::Cap => "cap",
+            FpsPolicy::Force => "force",
+        }
+    }
+}
+
+/// A frame rate as a fraction, so NTSC rates such as 30000/1001 stay exact.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameRate {
+    num: u32,
+    den: u32,
+}
+
+/// Rates cameras, screen recorders and displays use.
+const STANDARD_RATES: &[(u32, u32)] = &[
+    (10, 1),
+    (12, 1),
+    (15, 1),
+    (20, 1),
+    (24000, 1001),
+    (24, 1),
+    (25, 1),
+    (30000, 1001),
+    (30, 1),
+    (48, 1),
+    (50, 1),
+    (60000, 1001),
+    (60, 1),
+    (90, 1),
+    (100, 1),
+    (120, 1),
+    (144, 1),
+    (240, 1),
+];
+
+/// How far a measured rate may be off a standard rate to be snapped to it.
+const SNAP_TOLERANCE: f64 = 0.03;
+
+/// The same for NTSC rates, which sit only 0.1% below the whole rates: an
+/// average that is merely close to one is a variable whole rate.
+const NTSC_TOLERANCE: f64 = 0.001;
+
+/// Lowest share of the cap a divided rate may drop to; below it the cap
+/// itself judders less than the choppy divided rate.
+const MIN_DIVIDED_SHARE: f64 = 2.0 / 3.0;
+
+impl FrameRate {
+    pub fn whole(fps: u32) -> FrameRate {
+        FrameRate { num: fps, den: 1 }
+    }
+
+    /// The standard rate closest to a measured `fps`, such as 30000/1001
+    /// for 29.97 or 60 for the 59.8 average of a variable frame rate
+    /// recording. Other rates are rounded to whole frames.
+    pub fn clean(fps: f64) -> FrameRate {
+        let closest = STANDARD_RATES
+            .iter()
+            .map(|&(num, den)| FrameRate { num, den })
+            .map(|rate| (rate, (rate.as_f64() - fps).abs() / rate.as_f64()))
+            .filter(|(rate, off)| {
+                *off <= if rate.den == 1001 {
+                    NTSC_TOLERANCE
+                } else {
+                    SNAP_TOLERANCE
+                }
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
+        match closest {
+            Some((rate, _)) => rate,
+            None => FrameRate::whole(fps.round().max(1.0) as u32),
+        }
+    }
+
+    pub fn as_f64(self) -> f64 {
+        f64::from(self.num) / f64::from(self.den)
+    }
+
+    /// Frames per second rounded up, for limits that count whole frames.
+    pub fn ceil(self) -> u32 {
+        self.num.div_ceil(self.den)
+    }
+
+    /// Every `divisor`th frame of this rate.
+    fn divided(self, divisor: u32) -> FrameRate {
+        let den = self.den * divisor;
+        let gcd = gcd(self.num, den);
+        FrameRate {
+            num: self.num / gcd,
+            den: den / gcd,
+        }
+    }
+
+    /// The rate as ffmpeg takes it, e.g. `30000/1001`.
+    pub fn to_arg(self) -> String {
+        if self.den == 1 {
+            self.num.to_string()
+        } else {
+            format!("{}/{}", self.num, self.den)
+        }
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// The rate the source runs at: its nominal rate, or the standard rate
+/// closest to its average when the frame rate is variable.
+pub fn source_rate(video: &VideoStream) -> Option<FrameRate> {
+    let fps = if video.is_vfr() {
+        video.avg_frame_rate
+    } else {
+        video.frame_rate.or(video.avg_frame_rate)
+    };
+    fps.map(FrameRate::clean)
+}
+
+/// The constant rate the output is encoded at, or `None` to keep the
+/// source's frame timing as it is.
+pub fn output_rate(video: Option<&VideoStream>, preset: &Preset) -> Option<FrameRate> {
+    let target = FrameRate::whole(preset.fps);
+    let source = video.and_then(source_rate);
+    // Some clients stutter on variable frame rates, so those get the rate
+    // the source averages
+    let needs_cfr = preset.constant_frame_rate && video.is_some_and(VideoStream::is_vfr);
+
+    match (preset.fps_policy, source) {
+        (FpsPolicy::Force, _) => Some(target),
+        (FpsPolicy::Keep, source) => source.filter(|_| needs_cfr),
+        (FpsPolicy::Cap, None) => Some(target),
+        // NTSC rates count as their whole rate
+        (FpsPolicy::Cap, Some(source)) if source.as_f64() <= target.as_f64() * 1.001 => {
+            needs_cfr.then_some(source)
+        }
+        (FpsPolicy::Cap, Some(source)) => Some(capped(source, target)),
+    }
+}
+
+/// `source` divided by the smallest whole number that brings it under
+/// `cap`, so frames are dropped evenly: 60 under a cap of 30 is 30, under
+/// a cap of 25 it is 20.
+fn capped(source: FrameRate, cap: FrameRate) -> FrameRate {
+    let divisor = (source.as_f64() / cap.as_f64() - 1e-3).ceil().max(1.0) as u32;
+    let divided = source.divided(divisor);
+    if divided.as_f64() < cap.as_f64() * MIN_DIVIDED_SHARE {
+        cap
+    } else {
+        divided
+    }
+}
+
+/// Why a copy of `video` doesn't fit the preset's frame rate policy.
+pub fn needs_encode(video: &VideoStream, preset: &Preset) -> Option<String> {
+    let output = output_rate(Some(video), preset)?;
+    let source = source_rate(video)?;
+    if video.is_vfr() {
+        Some(format!(
+            "the frame rate is variable, the {} preset plays {}fps",
+            preset.name, output
+        ))
+    } else if source != output {
+        Some(format!(
+            "{}fps, the {} preset plays {}fps",
+            source, preset.name, output
+        ))
+    } else {
+        None
+    }
+}
+
+/// What happens to the frame rate, for the settings summary.
+pub fn describe(video: Option<&VideoStream>, output: Option<FrameRate>) -> String {
+    let source = video.and_then(source_rate);
+    let vfr = video.is_some_and(VideoStream::is_vfr);
+    match (source, output) {
+        (Some(source), Some(output)) if vfr => {
+            format!("variable (about {}fps) → constant {}fps", source, output)
+        }
+        (Some(source), Some(output)) if source == output => format!("{}fps", output),
+        (Some(source), Some(output)) => format!("{} → {}fps", source, output),
+        (None, Some(output)) => format!("{}fps", output),
+        (Some(source), None) if vfr => format!("variable (about {}fps), kept", source),
+        (Some(source), None) => format!("{}fps, kept", source),
+        (None, None) => "kept".to_string(),
+    }
+}
+
+impl fmt::Display for FrameRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.den == 1 {
+            write!(f, "{}", self.num)
+        } else {
+            write!(f, "{:.2}", self.as_f64())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset;
+    use crate::probe::ColorInfo;
+
+    fn video(frame_rate: f64, avg_frame_rate: f64) -> VideoStream {
+        VideoStream {
+            index: 0,
+            codec_name: "h264".to_string(),
+            profile: None,
+            level: None,
+            width: 1920,
+            height: 1080,
+            frame_rate: Some(frame_rate),
+            avg_frame_rate: Some(avg_frame_rate),
+            pix_fmt: None,
+            alpha: false,
+            color: ColorInfo::default(),
+            rotation: 0,
+            bit_rate: None,
+            duration: None,
+            frame_count: None,
+        }
+    }
+
+    fn preset(name: &str, policy: FpsPolicy, fps: u32) -> Preset {
+        Preset {
+            fps_policy: policy,
+            fps,
+            ..preset::builtin(name).unwrap()
+        }
+    }
+
+    const NTSC: FrameRate = FrameRate {
+        num: 30000,
+        den: 1001,
+    };
+
+    #[test]
+    fn snaps_to_standard_rates() {
+        assert_eq!(FrameRate::clean(30000.0 / 1001.0), NTSC);
+        assert_eq!(FrameRate::clean(59.8), FrameRate::whole(60));
+        assert_eq!(FrameRate::clean(29.9), FrameRate::whole(30));
+        assert_eq!(FrameRate::clean(25.0), FrameRate::whole(25));
+        // Nothing standard within 3%
+        assert_eq!(FrameRate::clean(37.2), FrameRate::whole(37));
+        assert_eq!(FrameRate::clean(0.2), FrameRate::whole(1));
+    }
+
+    #[test]
+    fn divides_fast_sources_evenly() {
+        let cap = |source: f64, fps: u32| {
+            output_rate(
+                Some(&video(source, source)),
+                &preset("mobile-safe", FpsPolicy::Cap, fps),
+            )
+        };
+        assert_eq!(cap(60.0, 30), Some(FrameRate::whole(30)));
+        assert_eq!(cap(60.0, 25), Some(FrameRate::whole(20)));
+        assert_eq!(cap(50.0, 30), Some(FrameRate::whole(25)));
+        assert_eq!(
+            cap(60000.0 / 1001.0, 30),
+            Some(NTSC),
+            "59.94 halves to 29.97"
+        );
+        // 30 at a cap of 24 would drop to 15, so the cap is used instead
+        assert_eq!(cap(30.0, 24), Some(FrameRate::whole(24)));
+    }
+
+    #[test]
+    fn keeps_rates_under_the_cap() {
+        let policy = preset("mobile-safe", FpsPolicy::Cap, 30);
+        assert_eq!(output_rate(Some(&video(15.0, 15.0)), &policy), None);
+        let ntsc = video(30000.0 / 1001.0, 30000.0 / 1001.0);
+        assert_eq!(source_rate(&ntsc), Some(NTSC));
+        assert_eq!(output_rate(Some(&ntsc), &policy), None);
+        assert_eq!(needs_encode(&ntsc, &policy), None);
+        // Without a source rate the cap is all there is
+        assert_eq!(output_rate(None, &policy), Some(FrameRate::whole(30)));
+    }
+
+    #[test]
+    fn makes_variable_rates_constant_where_needed() {
+        // A 60fps screen recording that averages 59.8
+        let vfr = video(120.0, 59.8);
+        assert!(vfr.is_vfr());
+        assert_eq!(source_rate(&vfr), Some(FrameRate::whole(60)));
+
+        let keep = |name: &str| output_rate(Some(&vfr), &preset(name, FpsPolicy::Keep, 30));
+        assert_eq!(keep("mobile-safe"), None);
+        assert_eq!(keep("animation"), Some(FrameRate::whole(60)));
+
+        let animation = preset("animation", FpsPolicy::Cap, 30);
+        assert_eq!(
+            output_rate(Some(&vfr), &animation),
+            Some(FrameRate::whole(30))
+        );
+        assert!(needs_encode(&vfr, &animation).is_some());
+    }
+
+    #[test]
+    fn forces_the_rate() {
+        let force = preset("mobile-safe", FpsPolicy::Force, 24);
+        assert_eq!(
+            output_rate(Some(&video(15.0, 15.0)), &force),
+            Some(FrameRate::whole(24))
+        );
+        assert_eq!(
+            needs_encode(&video(15.0, 15.0), &force),
+            Some("15fps, the mobile-safe preset plays 24fps".to_string())
+        );
+    }
+
+    #[test]
+    fn writes_rates_for_ffmpeg_and_people() {
+        assert_eq!(NTSC.to_arg(), "30000/1001");
+        assert_eq!(NTSC.to_string(), "29.97");
+        assert_eq!(NTSC.ceil(), 30);
+        assert_eq!(FrameRate::whole(60).divided(3).to_arg(), "20");
+        assert_eq!(
+            FrameRate {
+                num: 24000,
+                den: 1001
+            }
+            .divided(2)
+            .to_arg(),
+            "12000/1001"
+        );
+    }
+}
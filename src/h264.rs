@@ -0,0 +1,152 @@
+//! H.264 profiles and level limits, from table A-1 of the H.264
+//! specification.
+
+/// Limits of one level. Levels are written as `level_idc`, the level
+/// multiplied by ten (31 for level 3.1), like ffprobe reports them.
+struct Level {
+    idc: u32,
+    /// Macroblocks per second
+    max_mbps: u64,
+    /// Macroblocks per frame
+    max_fs: u64,
+    /// Bitrate in kbps for the baseline and main profiles
+    max_br: u64,
+    /// Buffer size in kbit for the baseline and main profiles
+    max_cpb: u64,
+}
+
+#[rustfmt::skip]
+const LEVELS: &[Level] = &[
+    Level { idc: 10, max_mbps: 1_485, max_fs: 99, max_br: 64, max_cpb: 175 },
+    Level { idc: 11, max_mbps: 3_000, max_fs: 396, max_br: 192, max_cpb: 500 },
+    Level { idc: 12, max_mbps: 6_000, max_fs: 396, max_br: 384, max_cpb: 1_000 },
+    Level { idc: 13, max_mbps: 11_880, max_fs: 396, max_br: 768, max_cpb: 2_000 },
+    Level { idc: 20, max_mbps: 11_880, max_fs: 396, max_br: 2_000, max_cpb: 2_000 },
+    Level { idc: 21, max_mbps: 19_800, max_fs: 792, max_br: 4_000, max_cpb: 4_000 },
+    Level { idc: 22, max_mbps: 20_250, max_fs: 1_620, max_br: 4_000, max_cpb: 4_000 },
+    Level { idc: 30, max_mbps: 40_500, max_fs: 1_620, max_br: 10_000, max_cpb: 10_000 },
+    Level { idc: 31, max_mbps: 108_000, max_fs: 3_600, max_br: 14_000, max_cpb: 14_000 },
+    Level { idc: 32, max_mbps: 216_000, max_fs: 5_120, max_br: 20_000, max_cpb: 20_000 },
+    Level { idc: 40, max_mbps: 245_760, max_fs: 8_192, max_br: 20_000, max_cpb: 25_000 },
+    Level { idc: 41, max_mbps: 245_760, max_fs: 8_192, max_br: 50_000, max_cpb: 62_500 },
+    Level { idc: 42, max_mbps: 522_240, max_fs: 8_704, max_br: 50_000, max_cpb: 62_500 },
+    Level { idc: 50, max_mbps: 589_824, max_fs: 22_080, max_br: 135_000, max_cpb: 135_000 },
+    Level { idc: 51, max_mbps: 983_040, max_fs: 36_864, max_br: 240_000, max_cpb: 240_000 },
+    Level { idc: 52, max_mbps: 2_073_600, max_fs: 36_864, max_br: 240_000, max_cpb: 240_000 },
+];
+
+/// What a stream asks of the decoder.
+pub struct Stream {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    /// Peak bitrate in kbps
+    pub max_bitrate: u32,
+    /// Rate control buffer in kbit
+    pub buffer_size: u32,
+}
+
+/// The lowest level that can carry `stream` in `profile`, or `None` when it
+/// is beyond even the highest level.
+pub fn level_for(profile: Option<&str>, stream: &Stream) -> Option<u32> {
+    let (num, den) = bitrate_factor(profile);
+
+    let width_mbs = u64::from(stream.width.div_ceil(16));
+    let height_mbs = u64::from(stream.height.div_ceil(16));
+    let frame_mbs = width_mbs * height_mbs;
+
+    LEVELS
+        .iter()
+        .find(|level| {
+            // Neither side may be longer than a square frame of the
+            // level's size allows
+            let max_side = ((level.max_fs * 8) as f64).sqrt() as u64;
+            frame_mbs <= level.max_fs
+                && width_mbs <= max_side
+                && height_mbs <= max_side
+                && frame_mbs * u64::from(stream.fps) <= level.max_mbps
+                && u64::from(stream.max_bitrate) * den <= level.max_br * num
+                && u64::from(stream.buffer_size) * den <= level.max_cpb * num
+        })
+        .map(|level| level.idc)
+}
+
+/// Peak bitrate in kbps and buffer size in kbit that level `idc` allows
+/// in `profile`.
+pub fn max_rates(profile: Option<&str>, idc: u32) -> Option<(u64, u64)> {
+    let (num, den) = bitrate_factor(profile);
+    LEVELS
+        .iter()
+        .find(|level| level.idc == idc)
+        .map(|level| (level.max_br * num / den, level.max_cpb * num / den))
+}
+
+/// The high profile allows a quarter more bitrate at every level.
+fn bitrate_factor(profile: Option<&str>) -> (u64, u64) {
+    match profile {
+        Some("high") => (5, 4),
+        _ => (1, 1),
+    }
+}
+
+/// Most macroblocks a frame may have at level `idc`.
+pub fn max_frame_macroblocks(idc: u32) -> Option<u64> {
+    LEVELS
+        .iter()
+        .find(|level| level.idc == idc)
+        .map(|level| level.max_fs)
+}
+
+/// Position of `profile` in baseline < main < high, for the names
+/// presets use as well as the ones ffprobe reports. Constrained baseline
+/// counts as baseline; other profiles are `None`.
+pub fn profile_rank(profile: &str) -> Option<u32> {
+    match profile.to_ascii_lowercase().as_str() {
+        "baseline" | "constrained baseline" => Some(0),
+        "main" => Some(1),
+        "high" => Some(2),
+        _ => None,
+    }
+}
+
+/// `31` → `3.1`
+pub fn format_level(idc: u32) -> String {
+    format!("{}.{}", idc / 10, idc % 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(width: u32, height: u32, fps: u32, max_bitrate: u32) -> Stream {
+        Stream {
+            width,
+            height,
+            fps,
+            max_bitrate,
+            buffer_size: max_bitrate * 2,
+        }
+    }
+
+    #[test]
+    fn picks_the_lowest_level() {
+        assert_eq!(level_for(None, &stream(1280, 720, 30, 2000)), Some(31));
+        assert_eq!(level_for(None, &stream(1920, 1080, 30, 6000)), Some(40));
+        assert_eq!(level_for(None, &stream(1920, 1080, 60, 6000)), Some(42));
+        // Frames count in whole macroblocks: 1108x830 is 70x52
+        assert_eq!(level_for(None, &stream(1108, 830, 30, 0)), Some(32));
+        assert_eq!(level_for(None, &stream(7680, 4320, 30, 0)), None);
+    }
+
+    #[test]
+    fn allows_high_more_bitrate() {
+        let fast = Stream {
+            buffer_size: 16_000,
+            ..stream(1280, 720, 30, 16_000)
+        };
+        assert_eq!(level_for(None, &fast), Some(32));
+        assert_eq!(level_for(Some("high"), &fast), Some(31));
+        assert_eq!(max_rates(None, 31), Some((14_000, 14_000)));
+        assert_eq!(max_rates(Some("high"), 31), Some((17_500, 17_500)));
+    }
+}
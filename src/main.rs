@@ -1,178 +1,1058 @@
-use clap::Parser;
-use std::path::Path;
+mod batch;
+mod codec;
+mod compat;
+mod config;
+mod encode;
+mod framerate;
+mod h264;
+mod json;
+mod preset;
+mod priority;
+mod probe;
+mod progress;
+mod rate;
+mod rotate;
+mod scheduler;
+mod screen;
+mod split;
+mod telegram;
+mod tempdir;
+mod thumbnail;
+mod timestamp;
+mod toml;
+mod watch;
+
+use clap::{Parser, Subcommand};
+use compat::StreamAction;
+use preset::{Container, Preset};
+use rate::RateControl;
+use scheduler::Job;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
 use std::process::{Command, exit};
+use timestamp::Timestamp;
 
 #[derive(Parser)]
 #[command(name = "telegram-video-converter")]
 #[command(about = "Convert videos to Telegram Mobile compatible format")]
 #[command(version = "0.1.0")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// Input video files, directories or glob patterns to convert
+    #[arg(required = true)]
+    input: Vec<String>,
+
+    /// Descend into subdirectories of input directories
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Number of files to convert at the same time
+    #[arg(short, long, default_value = "1")]
+    jobs: usize,
+
+    /// Stop the whole batch at the first failed conversion
+    #[arg(long)]
+    fail_fast: bool,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+/// Conversion settings, shared by one-off conversions and the watch mode.
+#[derive(clap::Args)]
 struct Args {
-    /// Input video file to convert
-    input: String,
+    /// Only convert files with these extensions (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    include: Vec<String>,
+
+    /// Skip files with these extensions (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    exclude: Vec<String>,
 
-    /// Output file path (optional, defaults to input_telegram.mp4)
+    /// Output file path (optional, defaults to input_telegram.mp4 or .webm; single input only)
     #[arg(short, long)]
     output: Option<String>,
 
-    /// Video bitrate in kbps
-    #[arg(short, long, default_value = "2000")]
-    bitrate: u32,
+    /// Config file [default: $XDG_CONFIG_HOME/telegram-video-converter/config.toml]
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Named profile from the config file
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Encoding preset (mobile-safe, desktop, animation, video-note, story, sticker)
+    #[arg(short, long)]
+    preset: Option<String>,
+
+    /// Make a round video note: square, at most 640x640 and 60 seconds
+    #[arg(long, conflicts_with = "preset")]
+    video_note: bool,
+
+    /// Make a video sticker: VP9 WebM, 512px, at most 3 seconds and 256KB
+    #[arg(long, conflicts_with_all = ["preset", "video_note"])]
+    sticker: bool,
+
+    /// Make a silent MP4 that Telegram autoplays inline like a GIF
+    #[arg(long, conflicts_with_all = ["preset", "video_note", "sticker"])]
+    animation: bool,
+
+    /// Play the input this many extra times, e.g. to lengthen a short GIF
+    #[arg(long = "loop", value_name = "COUNT", default_value = "0")]
+    loop_count: u32,
+
+    /// Where to start in the (looped) input, e.g. 1:23.5, 83.5s or 2000f for a frame
+    #[arg(long, value_name = "TIME", value_parser = timestamp::parse)]
+    start: Option<Timestamp>,
+
+    /// Where to stop in the (looped) input
+    #[arg(long, value_name = "TIME", value_parser = timestamp::parse, conflicts_with = "duration")]
+    end: Option<Timestamp>,
+
+    /// How much to keep after --start
+    #[arg(long, value_name = "TIME", value_parser = timestamp::parse)]
+    duration: Option<Timestamp>,
+
+    /// Cut inputs longer than the preset's duration limit instead of failing
+    #[arg(long)]
+    trim_to_limit: bool,
+
+    /// Video codec; anything but h264 only plays on some Telegram clients [default: from preset]
+    #[arg(long, value_enum, conflicts_with = "sticker")]
+    codec: Option<codec::VideoCodec>,
+
+    /// How the video bitrate is chosen [default: from preset, abr with --target-size or --two-pass]
+    #[arg(long, value_enum)]
+    rate_control: Option<rate::RateMode>,
+
+    /// Video bitrate in kbps: the cap, average or constant rate [default: from preset]
+    #[arg(short, long)]
+    bitrate: Option<u32>,
+
+    /// Audio bitrate in kbps [default: from preset]
+    #[arg(short = 'a', long)]
+    audio_bitrate: Option<u32>,
+
+    /// Frame rate cap, or the frame rate with --fps-policy force [default: from preset]
+    #[arg(short, long)]
+    fps: Option<u32>,
+
+    /// Keep the source frame rate, cap it at --fps by dropping frames evenly, or force --fps [default: cap]
+    #[arg(long, value_enum)]
+    fps_policy: Option<framerate::FpsPolicy>,
+
+    /// CRF quality on the libx264 scale, mapped to the other codecs (lower = better quality, 18-28 recommended) [default: from preset]
+    #[arg(short, long)]
+    crf: Option<u32>,
 
-    /// Audio bitrate in kbps
-    #[arg(short = 'a', long, default_value = "128")]
-    audio_bitrate: u32,
+    /// Constant quantizer for --rate-control qp (0-51) [default: the CRF]
+    #[arg(long)]
+    qp: Option<u32>,
 
-    /// Frame rate
-    #[arg(short, long, default_value = "25")]
-    fps: u32,
+    /// Encoder speed; slower makes smaller files at the same quality [default: medium]
+    #[arg(long, value_enum)]
+    speed: Option<codec::Speed>,
 
-    /// CRF quality (lower = better quality, 18-28 recommended)
-    #[arg(short, long, default_value = "23")]
-    crf: u32,
+    /// Tune the encoder for the content [default: from preset]
+    #[arg(long, value_enum)]
+    tune: Option<codec::Tune>,
+
+    /// What the input shows; screen recordings drop repeated frames and keep text sharp [default: auto]
+    #[arg(long, value_enum)]
+    content: Option<screen::Content>,
+
+    /// Apply the recorded rotation to the pixels, or keep it as metadata [default: bake]
+    #[arg(long, value_enum)]
+    rotation: Option<rotate::RotationPolicy>,
+
+    /// Rotate the video clockwise by 90, 180 or 270 degrees
+    #[arg(long, value_name = "DEGREES", value_parser = rotate::parse_angle)]
+    rotate: Option<u32>,
+
+    /// Mirror the video (can be given twice)
+    #[arg(long, value_enum)]
+    flip: Vec<rotate::Flip>,
+
+    /// Largest output width in pixels, keeping the aspect ratio [default: from preset]
+    #[arg(long)]
+    max_width: Option<u32>,
+
+    /// Largest output height in pixels, keeping the aspect ratio [default: from preset]
+    #[arg(long)]
+    max_height: Option<u32>,
+
+    /// Largest output width or height, whichever is longer [default: from preset]
+    #[arg(long)]
+    max_dimension: Option<u32>,
+
+    /// Never scale inputs smaller than the size limits up
+    #[arg(long)]
+    no_upscale: bool,
+
+    /// Target output size (e.g. 50MB, 1.5GB); the video bitrate is computed from it
+    #[arg(short = 's', long, value_parser = parse_bytes)]
+    target_size: Option<u64>,
+
+    /// Split outputs larger than this into numbered parts (e.g. 2GB)
+    #[arg(long, value_parser = parse_bytes)]
+    split_size: Option<u64>,
+
+    /// Split outputs longer than this into numbered parts (e.g. 10m)
+    #[arg(long, value_name = "TIME", value_parser = timestamp::parse)]
+    split_duration: Option<Timestamp>,
+
+    /// Write a JPEG thumbnail (at most 320px and 200KB) next to the output
+    #[arg(long)]
+    thumbnail: bool,
+
+    /// Take the thumbnail at this time of the output instead of picking a frame
+    #[arg(long, value_name = "TIME", value_parser = timestamp::parse)]
+    thumbnail_at: Option<Timestamp>,
+
+    /// Also embed the thumbnail in the output as its cover art (MP4 only)
+    #[arg(long)]
+    embed_thumbnail: bool,
+
+    /// Upload the result with the Telegram Bot API (bot_token comes from the config)
+    #[arg(long)]
+    send: bool,
+
+    /// Chat to upload to [default: chat_id from the config]
+    #[arg(long)]
+    chat_id: Option<String>,
+
+    /// Caption of the uploaded video
+    #[arg(long)]
+    caption: Option<String>,
+
+    /// Bot API server, e.g. a self-hosted one [default: https://api.telegram.org]
+    #[arg(long)]
+    api_url: Option<String>,
+
+    /// Bot token, only read from the config so it stays out of shell history
+    #[arg(skip)]
+    bot_token: Option<String>,
 
     /// Overwrite output file if it exists
     #[arg(short = 'y', long)]
     overwrite: bool,
 
+    /// Encode in two passes at an average of --bitrate (implied by --target-size; needs abr)
+    #[arg(long)]
+    two_pass: bool,
+
+    /// Re-encode every stream even if the input is already compatible
+    #[arg(long)]
+    force_encode: bool,
+
+    /// Encoder threads per conversion [default: all cores, shared between --jobs]
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// Run ffmpeg at a low CPU and IO priority
+    #[arg(long, visible_alias = "low-priority")]
+    nice: bool,
+
     /// Show ffmpeg output (verbose mode)
     #[arg(short, long)]
     verbose: bool,
+
+    /// Config keys that filled a setting the command line left unset
+    #[arg(skip)]
+    config_keys: BTreeSet<String>,
+}
+
+impl Args {
+    /// Whether the `key` setting came from the config rather than the
+    /// command line.
+    fn set_by_config(&self, key: &str) -> bool {
+        self.config_keys.contains(key)
+    }
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Show what ffprobe reports about a video file
+    Info {
+        /// Video file to inspect
+        input: String,
+    },
+
+    /// List the built-in encoding presets
+    Presets,
+
+    /// Inspect the configuration file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
+    /// Watch a directory and convert recordings once they are finished
+    Watch {
+        /// Directory to watch, e.g. the OBS recordings directory
+        dir: String,
+
+        #[command(flatten)]
+        watch: watch::WatchOptions,
+
+        #[command(flatten)]
+        args: Args,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Print the effective settings after merging CLI, profile, config and preset
+    Show {
+        #[command(flatten)]
+        args: Args,
+    },
 }
 
 fn main() {
-    let args = Args::parse();
+    let cli = Cli::parse();
 
-    // Check if input file exists
-    if !Path::new(&args.input).exists() {
-        eprintln!("Error: File '{}' not found", args.input);
+    match cli.command {
+        Some(Commands::Info { input }) => info(&input),
+        Some(Commands::Presets) => presets(),
+        Some(Commands::Config {
+            command: ConfigCommands::Show { args },
+        }) => config_show(args),
+        Some(Commands::Watch { dir, watch, args }) => watch_dir(&dir, &watch, args),
+        None => convert(cli),
+    }
+}
+
+fn info(input: &str) {
+    check_input(input);
+
+    match probe::probe(input) {
+        Ok(media) => {
+            print!("{}", media);
+            if let Some(reason) = screen::detect(&media) {
+                println!("Screen recording: {}", reason);
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    }
+}
+
+fn presets() {
+    for name in preset::BUILTIN_NAMES {
+        if let Some(preset) = preset::builtin(name) {
+            println!("{}", preset);
+        }
+    }
+}
+
+/// Merge the config file into `args` and resolve the preset, or exit if
+/// either fails.
+fn load_settings(mut args: Args) -> (Args, Preset) {
+    let result = config::Config::load(args.config.clone())
+        .and_then(|config| config.apply(&mut args))
+        .and_then(|()| preset::resolve(&args));
+    match result {
+        Ok(preset) => {
+            rate::drop_unused(&mut args, preset.rate_mode);
+            (args, preset)
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    }
+}
+
+fn config_show(args: Args) {
+    let path = args.config.clone().unwrap_or_else(config::default_path);
+    let exists = path.exists();
+    let (args, preset) = load_settings(args);
+
+    println!(
+        "# Config: {}{}",
+        path.display(),
+        if exists { "" } else { " (not found)" }
+    );
+    if let Some(profile) = &args.profile {
+        println!("# Profile: {}", profile);
+    }
+
+    let list = |items: &[String]| {
+        let quoted: Vec<String> = items.iter().map(|i| format!("{:?}", i)).collect();
+        format!("[{}]", quoted.join(", "))
+    };
+    println!("preset = {:?}", preset.name);
+    println!("codec = {:?}", preset.video_codec.name());
+    println!("rate_control = {:?}", preset.rate_mode.name());
+    println!("bitrate = {}", preset.bitrate);
+    if let Some(audio) = &preset.audio {
+        println!("audio_bitrate = {}", audio.bitrate);
+    }
+    println!("fps_policy = {:?}", preset.fps_policy.name());
+    println!("fps = {}", preset.fps);
+    println!("crf = {}", preset.crf);
+    if let Some(qp) = preset.qp {
+        println!("qp = {}", qp);
+    }
+    println!("speed = {:?}", preset.speed.name());
+    match preset.tune {
+        Some(tune) => println!("tune = {:?}", tune.name()),
+        None => println!("# tune is not set"),
+    }
+    println!(
+        "content = {:?}",
+        args.content.unwrap_or(screen::Content::Auto).name()
+    );
+    let limits = [
+        ("max_width", preset.max_width),
+        ("max_height", preset.max_height),
+        ("max_dimension", preset.max_dimension),
+    ];
+    for (key, limit) in limits {
+        match limit {
+            Some(limit) => println!("{} = {}", key, limit),
+            None => println!("# {} is not set", key),
+        }
+    }
+    println!("no_upscale = {}", !preset.upscale);
+    println!("rotation = {:?}", rotate::policy(&args).name());
+    match args.target_size {
+        Some(size) => println!("target_size = {:?}", format_bytes(size)),
+        None => println!("# target_size is not set"),
+    }
+    match args.split_size {
+        Some(size) => println!("split_size = {:?}", format_bytes(size)),
+        None => println!("# split_size is not set"),
+    }
+    match args.split_duration {
+        Some(duration) => println!("split_duration = {:?}", duration.to_string()),
+        None => println!("# split_duration is not set"),
+    }
+    println!("trim_to_limit = {}", args.trim_to_limit);
+    if args.loop_count > 0 {
+        println!("loop = {}", args.loop_count);
+    }
+    println!("two_pass = {}", args.two_pass);
+    println!("force_encode = {}", args.force_encode);
+    println!("overwrite = {}", args.overwrite);
+    println!("verbose = {}", args.verbose);
+    match args.threads {
+        Some(threads) => println!("threads = {}", threads),
+        None => println!("# threads is not set"),
+    }
+    println!("nice = {}", args.nice);
+    println!("thumbnail = {}", args.thumbnail);
+    if let Some(at) = args.thumbnail_at {
+        println!("thumbnail_at = {:?}", at.to_string());
+    }
+    println!("embed_thumbnail = {}", args.embed_thumbnail);
+    println!("send = {}", args.send);
+    match &args.chat_id {
+        Some(chat_id) => println!("chat_id = {:?}", chat_id),
+        None => println!("# chat_id is not set"),
+    }
+    if let Some(caption) = &args.caption {
+        println!("caption = {:?}", caption);
+    }
+    println!(
+        "api_url = {:?}",
+        args.api_url.as_deref().unwrap_or(telegram::DEFAULT_API_URL)
+    );
+    // Never print the token itself
+    if args.bot_token.is_some() {
+        println!("# bot_token is set");
+    } else {
+        println!("# bot_token is not set");
+    }
+    println!("include = {}", list(&args.include));
+    println!("exclude = {}", list(&args.exclude));
+}
+
+fn watch_dir(dir: &str, options: &watch::WatchOptions, args: Args) {
+    check_tools();
+    let (args, preset) = load_settings(args);
+
+    if args.output.is_some() {
+        eprintln!("Error: --output can't be used when watching a directory");
         exit(1);
     }
 
-    // Check if ffmpeg is installed
-    if !is_ffmpeg_available() {
-        eprintln!("Error: ffmpeg is not installed or not in PATH");
+    lower_priority(&args);
+
+    let filter = batch::Filter::new(&args.include, &args.exclude);
+    let result = watch::run(dir, options, &filter, preset.container, |input| {
+        convert_file(input, &args, &preset, &Job::standalone())
+    });
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
         exit(1);
     }
+}
+
+fn convert(cli: Cli) {
+    check_tools();
+
+    let (args, preset) = load_settings(cli.args);
+    lower_priority(&args);
+    let filter = batch::Filter::new(&args.include, &args.exclude);
+    let inputs = match batch::collect_inputs(&cli.input, cli.recursive, &filter) {
+        Ok(inputs) => inputs,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    };
+
+    if inputs.len() > 1 && args.output.is_some() {
+        eprintln!("Error: --output can only be used with a single input file");
+        exit(1);
+    }
+
+    // A single file keeps the plain output of the original tool
+    if let [input] = inputs.as_slice() {
+        if let Err(e) = convert_file(input, &args, &preset, &Job::standalone()) {
+            eprintln!("✗ {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    let results = scheduler::run(&inputs, cli.jobs, cli.fail_fast, |input, job| {
+        convert_file(input, &args, &preset, job)
+    });
+
+    batch::print_summary(&results);
+    if results.iter().any(|(_, result)| result.is_err()) {
+        exit(1);
+    }
+}
+
+/// How much the CRF goes up on each retry to fit a size limit.
+const CRF_RETRY_STEP: u32 = 6;
+
+/// Lowest video bitrate (kbps) a size limit retry goes down to.
+const MIN_RETRY_BITRATE: u32 = 50;
+
+/// Sizes of a finished conversion, for the batch summary.
+pub struct Converted {
+    pub input_size: u64,
+    pub output_size: u64,
+}
+
+fn convert_file(input: &str, args: &Args, preset: &Preset, job: &Job) -> Result<Converted, String> {
+    // Check if input file exists
+    if !Path::new(input).exists() {
+        return Err(format!("File '{}' not found", input));
+    }
+
+    // Find out whether uploading can work before spending time converting
+    let bot = args
+        .send
+        .then(|| telegram::Bot::from_args(args))
+        .transpose()?;
+
+    // Make sure the input is actually something ffmpeg can read
+    let media = probe::probe(input)?;
+
+    // Screen recordings get their own tuning on top of the preset
+    let mut preset = preset.clone();
+    let screen = match args.content.unwrap_or(screen::Content::Auto) {
+        screen::Content::Auto => screen::detect(&media),
+        screen::Content::Screen => Some("--content screen".to_string()),
+        screen::Content::Other => None,
+    };
+    if screen.is_some() {
+        screen::tune(&mut preset, args);
+    }
 
     // Generate output filename
     let output_path = args
         .output
-        .unwrap_or_else(|| generate_output_path(&args.input));
+        .clone()
+        .unwrap_or_else(|| generate_output_path(input, preset.container));
 
     // Check if output file exists and overwrite flag
     if Path::new(&output_path).exists() && !args.overwrite {
-        eprintln!(
-            "Error: Output file '{}' already exists. Use -y to overwrite.",
+        return Err(format!(
+            "Output file '{}' already exists. Use -y to overwrite.",
             output_path
-        );
-        exit(1);
+        ));
     }
 
-    println!("Converting '{}' for Telegram compatibility...", args.input);
-    println!("Output: '{}'", output_path);
-    println!(
-        "Settings: {}kbps video, {}kbps audio, {}fps, CRF {}",
-        args.bitrate, args.audio_bitrate, args.fps, args.crf
-    );
+    let thumbnail_path = (args.thumbnail || args.thumbnail_at.is_some() || args.embed_thumbnail)
+        .then(|| thumbnail::thumbnail_path(&output_path));
+    if let Some(path) = &thumbnail_path {
+        if Path::new(path).exists() && !args.overwrite {
+            return Err(format!(
+                "Thumbnail '{}' already exists. Use -y to overwrite.",
+                path
+            ));
+        }
+        if args.embed_thumbnail && preset.container != Container::Mp4 {
+            return Err(format!(
+                "--embed-thumbnail needs MP4 output, but the {} preset writes {}",
+                preset.name,
+                preset.container.extension()
+            ));
+        }
+    }
+
+    job.say(format!(
+        "Converting '{}' for Telegram compatibility...",
+        input
+    ));
+    job.say(format!("Output: '{}'", output_path));
+
+    // Only re-encode the streams Telegram can't play as they are
+    let plan = compat::plan(&media, args, &preset);
+    if let Some(reason) = &screen
+        && matches!(plan.video, StreamAction::Encode(_))
+    {
+        job.say(format!(
+            "Screen recording ({}): {}keeping text sharp",
+            reason,
+            if preset.decimate {
+                "dropping repeated frames and "
+            } else {
+                ""
+            }
+        ));
+    }
+    if plan.is_remux() {
+        job.say("Input is already compatible, remuxing without re-encoding");
+    } else {
+        let audio = preset
+            .audio
+            .as_ref()
+            .map_or("no".to_string(), |a| format!("{}kbps", a.bitrate));
+        job.say(format!(
+            "Settings: {} preset, {} video at {} speed, {} audio",
+            preset.name,
+            preset.video_codec.label(),
+            preset.speed.name(),
+            audio
+        ));
+    }
+    let video = media.video();
+    let frame_rate = framerate::output_rate(video, &preset);
+    if matches!(plan.video, StreamAction::Encode(_)) {
+        job.say(format!(
+            "Frame rate: {}",
+            framerate::describe(video, frame_rate)
+        ));
+    }
+    // Stickers are VP9 on every client, so only videos get the note
+    if !preset.video_codec.plays_everywhere()
+        && preset.container == Container::Mp4
+        && plan.video != StreamAction::Missing
+    {
+        job.say(format!(
+            "Note: {} video only plays inline on Telegram for {}",
+            preset.video_codec.label(),
+            preset.video_codec.clients().join(", ")
+        ));
+    }
+    job.say(format!("Video: {}", plan.video));
+    job.say(format!("Audio: {}", plan.audio));
+
+    // Looping repeats the whole input, then --start, --end and --duration
+    // pick the part of it to keep
+    let fps = video.and_then(|v| v.frame_rate.or(v.avg_frame_rate));
+    let looped = media.duration().map(|d| d * f64::from(args.loop_count + 1));
+    let start = args.start.map(|t| t.seconds(fps)).transpose()?;
+    let length = match (args.end, args.duration) {
+        (Some(end), _) => Some(end.seconds(fps)? - start.unwrap_or(0.0)),
+        (None, Some(duration)) => Some(duration.seconds(fps)?),
+        (None, None) => None,
+    };
+    if length.is_some_and(|l| l <= 0.0) {
+        return Err("Nothing left to convert: the end is not after the start".to_string());
+    }
+    let remaining = looped.map(|l| l - start.unwrap_or(0.0));
+    if remaining.is_some_and(|r| r <= 0.0) {
+        return Err(format!(
+            "--start is past the end of the {:.1}s input",
+            looped.unwrap_or(0.0)
+        ));
+    }
+    let mut trim_to = length.filter(|&l| remaining.is_none_or(|r| l < r));
+    if let Some(start) = start {
+        job.say(format!(
+            "Keeping {} from {:.2}s",
+            trim_to.map_or("the rest".to_string(), |l| format!("{:.2}s", l)),
+            start
+        ));
+    }
+
+    // Parts get a keyframe at every cut when their length is known up front
+    let split_limits = split::Limits {
+        size: args.split_size,
+        duration: args.split_duration.map(|t| t.seconds(fps)).transpose()?,
+    };
+    if split_limits.size == Some(0) {
+        return Err("--split-size must be positive".to_string());
+    }
+    if split_limits.duration.is_some_and(|d| d <= 0.0) {
+        return Err("--split-duration must be positive".to_string());
+    }
+    if split_limits.size.is_some() || split_limits.duration.is_some() {
+        let first_part = split::part_path(&output_path, "1");
+        if Path::new(&first_part).exists() && !args.overwrite {
+            return Err(format!(
+                "Output file '{}' already exists. Use -y to overwrite.",
+                first_part
+            ));
+        }
+    }
 
-    // Build ffmpeg command
-    let mut cmd = Command::new("ffmpeg");
-
-    // Input file
-    cmd.args(["-i", &args.input]);
-
-    // Overwrite flag
-    if args.overwrite {
-        cmd.arg("-y");
-    }
-
-    // Video encoding settings
-    cmd.args([
-        "-c:v",
-        "libx264",
-        "-profile:v",
-        "baseline",
-        "-level",
-        "3.0",
-        "-pix_fmt",
-        "yuv420p",
-        "-crf",
-        &args.crf.to_string(),
-        "-maxrate",
-        &format!("{}k", args.bitrate),
-        "-bufsize",
-        &format!("{}k", args.bitrate * 2),
-        "-r",
-        &args.fps.to_string(),
-    ]);
-
-    // Audio encoding settings
-    cmd.args([
-        "-c:a",
-        "aac",
-        "-ar",
-        "44100",
-        "-ac",
-        "2",
-        "-b:a",
-        &format!("{}k", args.audio_bitrate),
-    ]);
-
-    // Output format and optimizations
-    cmd.args(["-movflags", "+faststart", "-f", "mp4", &output_path]);
-
-    // Hide ffmpeg output unless verbose
-    if !args.verbose {
-        cmd.args(["-loglevel", "error"]);
+    // Media with a length limit is either cut at the limit or rejected
+    if let (Some(max), Some(length)) = (preset.max_duration, trim_to.or(remaining))
+        && length > max
+    {
+        if !args.trim_to_limit {
+            return Err(format!(
+                "Output would be {:.1}s long, but the {} preset allows at most {}s. \
+                 Use --trim-to-limit to keep only the first {}s.",
+                length, preset.name, max, max
+            ));
+        }
+        job.say(format!("Trimming to the {}s limit", max));
+        trim_to = Some(max);
     }
+    let output_duration = trim_to.or(remaining);
+
+    // Work out the video bitrate that lands the output under the target size
+    let target_bitrate = match args.target_size {
+        Some(target_size) if matches!(plan.video, StreamAction::Encode(_)) => {
+            let preset_audio_bitrate = preset.audio.as_ref().map_or(0, |a| a.bitrate);
+            let audio_bitrate = match plan.audio {
+                StreamAction::Copy => media
+                    .audio()
+                    .and_then(|a| a.bit_rate)
+                    .map_or(preset_audio_bitrate, |b| (b / 1000) as u32),
+                StreamAction::Encode(_) => preset_audio_bitrate,
+                StreamAction::Drop | StreamAction::Missing => 0,
+            };
+            let bitrate = output_duration
+                .ok_or_else(|| "input duration is unknown".to_string())
+                .and_then(|d| encode::bitrate_for_size(target_size, d, audio_bitrate))
+                .map_err(|e| format!("Cannot reach target size: {}", e))?;
+            job.say(format!(
+                "Target size: {} → {}kbps video",
+                format_bytes(target_size),
+                bitrate
+            ));
+            Some(bitrate)
+        }
+        _ => None,
+    };
+
+    // Keep transparency only when the input has some to keep
+    if preset.pix_fmt == "yuva420p" && !video.is_some_and(|v| v.alpha) {
+        preset.pix_fmt = "yuv420p".to_string();
+    }
+    let mut rate = RateControl::for_preset(&preset, target_bitrate);
+    let two_pass =
+        matches!(rate, RateControl::Abr(_)) && (args.two_pass || args.target_size.is_some());
+    if matches!(plan.video, StreamAction::Encode(_)) {
+        job.say(format!(
+            "Rate control: {}{}",
+            rate.describe(preset.video_codec),
+            if two_pass { " in two passes" } else { "" }
+        ));
+    }
+
+    // Ask for the lowest H.264 level the output fits in, and warn when that
+    // is more than the preset's clients decode
+    let level = match (&plan.video, video) {
+        (StreamAction::Encode(_), Some(v)) if preset.max_level.is_some() => {
+            let (width, height) = rotate::frame_size(v, args);
+            let (width, height) = encode::output_size(&preset, width, height);
+            // Uncapped CRF is held to the bitrate of the level it gets, and
+            // x264 can't cap a constant quantizer, so only frames count then
+            let max_bitrate = rate.max_bitrate();
+            let fps = frame_rate
+                .or(framerate::source_rate(v))
+                .map_or(preset.fps, framerate::FrameRate::ceil);
+            let stream = h264::Stream {
+                width,
+                height,
+                fps,
+                max_bitrate: max_bitrate.unwrap_or(0),
+                buffer_size: max_bitrate.unwrap_or(0) * 2,
+            };
+            let level = h264::level_for(preset.profile.as_deref(), &stream);
+            match (level, preset.max_level) {
+                (Some(level), Some(max_level)) if level <= max_level => {}
+                (needed, Some(max_level)) => job.warn(format!(
+                    "Warning: {}x{} at {}fps{} needs H.264 level {}, \
+                     but clients of the {} preset only decode up to {}",
+                    width,
+                    height,
+                    fps,
+                    max_bitrate.map_or(String::new(), |b| format!(" and {}kbps", b)),
+                    needed.map_or("above 5.2".to_string(), h264::format_level),
+                    preset.name,
+                    h264::format_level(max_level)
+                )),
+                (_, None) => {}
+            }
+            level
+        }
+        _ => None,
+    };
 
     // Execute conversion
     let start_time = std::time::Instant::now();
-    let status = cmd.status();
-    let duration = start_time.elapsed();
+    let output_size = loop {
+        // Size targeting is only accurate with an analysis pass
+        let encode = encode::Encode {
+            input,
+            output: &output_path,
+            args,
+            plan: &plan,
+            preset: &preset,
+            decoder: video.and_then(|v| v.alpha_decoder()),
+            level,
+            loop_count: args.loop_count,
+            start,
+            keyframe_interval: split_limits.duration,
+            keep_rotation: video
+                .map(|v| v.rotation)
+                .filter(|&r| r != 0 && rotate::policy(args) == rotate::RotationPolicy::Metadata),
+            rate,
+            two_pass,
+            frame_rate,
+            trim_to,
+            duration: output_duration,
+            job,
+        };
+        encode.run()?;
+        let output_size = std::fs::metadata(&output_path).map_or(0, |m| m.len());
 
-    match status {
-        Ok(exit_status) => {
-            if exit_status.success() {
-                println!("✓ Conversion successful: {}", output_path);
-                println!("  Time taken: {:.2}s", duration.as_secs_f64());
-
-                // Show file sizes
-                if let (Ok(input_size), Ok(output_size)) = (
-                    std::fs::metadata(&args.input).map(|m| m.len()),
-                    std::fs::metadata(&output_path).map(|m| m.len()),
-                ) {
-                    println!("  Input size: {}", format_bytes(input_size));
-                    println!("  Output size: {}", format_bytes(output_size));
-                    let ratio = (output_size as f64 / input_size as f64) * 100.0;
-                    println!("  Size ratio: {:.1}%", ratio);
-                }
-            } else {
-                eprintln!(
-                    "✗ Conversion failed with exit code: {:?}",
-                    exit_status.code()
-                );
-                exit(1);
+        // Media with a hard size limit is encoded again at a lower quality
+        // until it fits
+        let Some(max_size) = preset.max_file_size else {
+            break output_size;
+        };
+        if output_size <= max_size || plan.is_remux() {
+            break output_size;
+        }
+        match &mut rate {
+            RateControl::Abr(bitrate) | RateControl::Cbr(bitrate)
+                if *bitrate > MIN_RETRY_BITRATE =>
+            {
+                let scaled = f64::from(*bitrate) * max_size as f64 / output_size as f64 * 0.95;
+                *bitrate = (scaled as u32).max(MIN_RETRY_BITRATE);
             }
+            RateControl::Crf(quality)
+            | RateControl::CappedCrf { crf: quality, .. }
+            | RateControl::Qp(quality)
+                if *quality < preset.video_codec.max_crf() =>
+            {
+                *quality = (*quality + CRF_RETRY_STEP).min(preset.video_codec.max_crf());
+            }
+            // Telegram refuses files over the limit, so there is nothing
+            // to send
+            _ => {
+                return Err(format!(
+                    "Output '{}' is {}, over the {} preset's {} limit even at the lowest \
+                     quality; try a shorter --duration or a lower --fps",
+                    output_path,
+                    format_bytes(output_size),
+                    preset.name,
+                    format_bytes(max_size)
+                ));
+            }
+        };
+        job.say(format!(
+            "Output is {}, over the {} limit; retrying with {}",
+            format_bytes(output_size),
+            format_bytes(max_size),
+            rate.describe(preset.video_codec)
+        ));
+        std::fs::remove_file(&output_path)
+            .map_err(|e| format!("Cannot remove '{}' to retry: {}", output_path, e))?;
+    };
+
+    // The thumbnail comes from the output, so it shows the final crop and
+    // rotation
+    let thumbnail = match &thumbnail_path {
+        Some(path) => {
+            let at = args
+                .thumbnail_at
+                .map(|t| {
+                    let fps = frame_rate
+                        .or(video.and_then(framerate::source_rate))
+                        .map_or(f64::from(preset.fps), framerate::FrameRate::as_f64);
+                    t.seconds(Some(fps))
+                })
+                .transpose()?;
+            let size = thumbnail::create(&output_path, path, at, output_duration, args, job)
+                .map_err(|e| format!("Cannot create thumbnail: {}", e))?;
+            Some((path, size))
         }
-        Err(e) => {
-            eprintln!("✗ Failed to execute ffmpeg: {}", e);
-            exit(1);
+        None => None,
+    };
+
+    // Outputs over the part limits are cut into numbered parts
+    let parts = if split_limits.exceeded_by(output_size, output_duration.unwrap_or(0.0)) {
+        let total =
+            output_duration.ok_or_else(|| "Cannot split: the duration is unknown".to_string())?;
+        Some(split::split(
+            &output_path,
+            preset.container,
+            output_size,
+            total,
+            &split_limits,
+            args,
+            job,
+        )?)
+    } else {
+        None
+    };
+    if let Some((path, _)) = thumbnail
+        && args.embed_thumbnail
+    {
+        let videos = match &parts {
+            Some(parts) => parts.iter().map(|(part, _)| part.as_str()).collect(),
+            None => vec![output_path.as_str()],
+        };
+        for video in videos {
+            thumbnail::embed(video, path, args, job)
+                .map_err(|e| format!("Cannot embed thumbnail: {}", e))?;
+        }
+    }
+    let duration = start_time.elapsed();
+
+    let written = match &parts {
+        Some(parts) => format!("{} parts", parts.len()),
+        None => output_path.clone(),
+    };
+    if plan.is_remux() {
+        job.say(format!("✓ Remux successful: {}", written));
+    } else {
+        job.say(format!("✓ Conversion successful: {}", written));
+    }
+    if let Some(parts) = &parts {
+        for (i, (path, size)) in parts.iter().enumerate() {
+            job.say(format!(
+                "  Part {}: {} ({})",
+                i + 1,
+                path,
+                format_bytes(*size)
+            ));
         }
     }
+    if let Some((path, size)) = thumbnail {
+        job.say(format!("  Thumbnail: {} ({})", path, format_bytes(size)));
+    }
+    job.say(format!("  Time taken: {:.2}s", duration.as_secs_f64()));
+
+    // Show file sizes
+    let input_size = std::fs::metadata(input).map_or(0, |m| m.len());
+    if input_size > 0 {
+        job.say(format!("  Input size: {}", format_bytes(input_size)));
+        job.say(format!("  Output size: {}", format_bytes(output_size)));
+        let ratio = (output_size as f64 / input_size as f64) * 100.0;
+        job.say(format!("  Size ratio: {:.1}%", ratio));
+    }
+
+    if let Some(target_size) = args.target_size
+        && output_size > target_size
+    {
+        job.warn(format!(
+            "  Warning: output is larger than the {} target",
+            format_bytes(target_size)
+        ));
+    }
+
+    if let Some(bot) = &bot {
+        let files: Vec<&str> = match &parts {
+            Some(parts) => parts.iter().map(|(part, _)| part.as_str()).collect(),
+            None => vec![output_path.as_str()],
+        };
+        for (i, file) in files.iter().enumerate() {
+            // Telegram shows the size and length before the video is
+            // downloaded, so they come from the file as written
+            let written = probe::probe(file)?;
+            let (width, height) = written.video().map_or((0, 0), |v| v.display_size());
+            job.say(format!("Sending '{}' to chat {}...", file, bot.chat_id()));
+            bot.send_video(&telegram::Video {
+                path: file,
+                width,
+                height,
+                duration: written.duration(),
+                thumbnail: thumbnail.map(|(path, _)| path.as_str()),
+                part: (files.len() > 1).then_some((i + 1, files.len())),
+            })?;
+        }
+        job.say(format!("✓ Sent to chat {}", bot.chat_id()));
+    }
+
+    Ok(Converted {
+        input_size,
+        output_size,
+    })
+}
+
+/// Lower the priority for `--nice` before any ffmpeg starts, as it is
+/// inherited from this process.
+fn lower_priority(args: &Args) {
+    if args.nice
+        && let Err(e) = priority::lower()
+    {
+        eprintln!("Warning: cannot lower the priority: {}", e);
+    }
+}
+
+/// Exit early when ffmpeg or ffprobe cannot be run.
+fn check_tools() {
+    // Check if ffmpeg is installed
+    if !is_ffmpeg_available() {
+        eprintln!("Error: ffmpeg is not installed or not in PATH");
+        exit(1);
+    }
+
+    // Check if ffprobe is installed (it ships with ffmpeg)
+    if !probe::is_ffprobe_available() {
+        eprintln!("Error: ffprobe is not installed or not in PATH");
+        exit(1);
+    }
+}
+
+/// Exit early when the input is missing or ffprobe cannot be run.
+fn check_input(input: &str) {
+    // Check if input file exists
+    if !Path::new(input).exists() {
+        eprintln!("Error: File '{}' not found", input);
+        exit(1);
+    }
+
+    // Check if ffprobe is installed (it ships with ffmpeg)
+    if !probe::is_ffprobe_available() {
+        eprintln!("Error: ffprobe is not installed or not in PATH");
+        exit(1);
+    }
 }
 
 fn is_ffmpeg_available() -> bool {
     Command::new("ffmpeg").arg("-version").output().is_ok()
 }
 
-fn generate_output_path(input_path: &str) -> String {
+fn generate_output_path(input_path: &str, container: Container) -> String {
     let path = Path::new(input_path);
     let parent = path.parent().unwrap_or(Path::new("."));
     let stem = path.file_stem().unwrap().to_str().unwrap();
 
     parent
-        .join(format!("{}_telegram.mp4", stem))
+        .join(format!("{}_telegram.{}", stem, container.extension()))
         .to_str()
         .unwrap()
         .to_string()
@@ -190,3 +1070,67 @@ fn format_bytes(bytes: u64) -> String {
 
     format!("{:.1} {}", size, UNITS[unit_index])
 }
+
+/// Parse a human-readable size such as `50MB`, `1.5 GB` or `512k`.
+/// Units are powers of 1024, matching `format_bytes`.
+fn parse_bytes(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", text))?;
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1u64,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => return Err(format!("unknown size unit '{}'", unit.trim())),
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_bytes("50MB"), Ok(50 << 20));
+        assert_eq!(parse_bytes("1.5 GB"), Ok(3 << 29));
+        assert_eq!(parse_bytes("512k"), Ok(512 << 10));
+        assert_eq!(parse_bytes(" 2 MiB "), Ok(2 << 20));
+        assert_eq!(parse_bytes("1000"), Ok(1000));
+        assert_eq!(parse_bytes("10b"), Ok(10));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert!(parse_bytes("").is_err());
+        assert!(parse_bytes("MB").is_err());
+        assert!(parse_bytes("-5MB").is_err());
+        assert!(parse_bytes("1.2.3MB").is_err());
+        assert_eq!(
+            parse_bytes("5 TB"),
+            Err("unknown size unit 'TB'".to_string())
+        );
+    }
+
+    #[test]
+    fn reads_back_formatted_sizes() {
+        // Sizes with one decimal in their unit come back exactly
+        for bytes in [0, 512, 1 << 10, 256 << 10, 50 << 20, 3 << 29] {
+            assert_eq!(parse_bytes(&format_bytes(bytes)), Ok(bytes));
+        }
+        // Others are off by at most the rounding to one decimal
+        for bytes in [1_234, 987_654, 45_000_000, 7_777_777_777] {
+            let parsed = parse_bytes(&format_bytes(bytes)).unwrap();
+            let unit = 1024f64.powi((bytes as f64).log(1024.0).floor() as i32);
+            assert!((parsed as f64 - bytes as f64).abs() <= 0.05 * unit);
+        }
+    }
+}
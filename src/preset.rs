@@ -0,0 +1,432 @@
+//! Named encoding presets for the different kinds of Telegram media.
+
+use crate::codec::{Speed, Tune, VideoCodec};
+use crate::framerate::FpsPolicy;
+use crate::h264;
+use crate::rate::{self, RateControl, RateMode};
+use crate::{Args, format_bytes};
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub struct Preset {
+    pub name: String,
+    pub description: String,
+    pub video_codec: VideoCodec,
+    /// H.264 profile the target clients decode
+    pub profile: Option<String>,
+    /// Highest H.264 level the target clients decode, multiplied by ten;
+    /// the level written to the output is the lowest one that fits it
+    pub max_level: Option<u32>,
+    pub speed: Speed,
+    /// Pixel format; one with alpha keeps the transparency of inputs that have it
+    pub pix_fmt: String,
+    pub tune: Option<Tune>,
+    pub rate_mode: RateMode,
+    /// CRF quality on the libx264 scale (lower = better quality)
+    pub crf: u32,
+    /// Video bitrate in kbps: the cap, the average or the constant rate,
+    /// depending on `rate_mode`
+    pub bitrate: u32,
+    /// Quantizer in `qp` mode [default: the CRF]
+    pub qp: Option<u32>,
+    pub fps_policy: FpsPolicy,
+    /// Frame rate cap, or the frame rate with the force policy
+    pub fps: u32,
+    /// Whether the target clients need a constant frame rate
+    pub constant_frame_rate: bool,
+    /// Drop frames that repeat the previous one, leaving a variable frame
+    /// rate of at most `fps`
+    pub decimate: bool,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Limit for the longest side, whichever way the video is oriented
+    pub max_dimension: Option<u32>,
+    /// Scale smaller inputs up to the size limits instead of keeping their size
+    pub upscale: bool,
+    /// Shrink frames the size limits let past `max_level` into it; off
+    /// when the limits are the user's own
+    pub fit_level: bool,
+    /// Longest output Telegram accepts for this kind of media, in seconds
+    pub max_duration: Option<f64>,
+    /// Largest file Telegram accepts for this kind of media, in bytes
+    pub max_file_size: Option<u64>,
+    /// Crop the center square out of the frame before scaling
+    pub square: bool,
+    /// Audio settings, `None` for silent media
+    pub audio: Option<AudioSettings>,
+    pub container: Container,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Container {
+    Mp4,
+    WebM,
+}
+
+impl Container {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::WebM => "webm",
+        }
+    }
+
+    /// ffmpeg muxer name
+    pub fn format(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::WebM => "webm",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioSettings {
+    pub codec: String,
+    /// Bitrate in kbps
+    pub bitrate: u32,
+    pub sample_rate: u32,
+    pub channels: u32,
+}
+
+pub const DEFAULT_PRESET: &str = "mobile-safe";
+
+pub const BUILTIN_NAMES: &[&str] = &[
+    "mobile-safe",
+    "desktop",
+    "animation",
+    "video-note",
+    "story",
+    "sticker",
+];
+
+fn aac(bitrate: u32, sample_rate: u32) -> Option<AudioSettings> {
+    Some(AudioSettings {
+        codec: "aac".to_string(),
+        bitrate,
+        sample_rate,
+        channels: 2,
+    })
+}
+
+/// Look up one of the built-in presets.
+pub fn builtin(name: &str) -> Option<Preset> {
+    let base = Preset {
+        name: name.to_string(),
+        description: String::new(),
+        video_codec: VideoCodec::H264,
+        profile: Some("baseline".to_string()),
+        max_level: Some(31),
+        speed: Speed::Medium,
+        pix_fmt: "yuv420p".to_string(),
+        tune: None,
+        rate_mode: RateMode::CappedCrf,
+        crf: 23,
+        bitrate: 2000,
+        qp: None,
+        fps_policy: FpsPolicy::Cap,
+        fps: 30,
+        constant_frame_rate: false,
+        decimate: false,
+        max_width: None,
+        max_height: None,
+        max_dimension: None,
+        upscale: false,
+        fit_level: true,
+        max_duration: None,
+        max_file_size: None,
+        square: false,
+        audio: aac(128, 44100),
+        container: Container::Mp4,
+    };
+
+    let preset = match name {
+        "mobile-safe" => Preset {
+            // Old phones decode baseline up to level 3.1, which tops out at
+            // 720p30 either way round
+            description: "Plays everywhere, including old phones".to_string(),
+            max_dimension: Some(1280),
+            ..base
+        },
+        "desktop" => Preset {
+            description: "High quality for Telegram Desktop and recent phones".to_string(),
+            profile: Some("high".to_string()),
+            max_level: Some(42),
+            crf: 20,
+            bitrate: 8000,
+            fps: 60,
+            max_dimension: Some(1920),
+            audio: aac(192, 48000),
+            ..base
+        },
+        "animation" => Preset {
+            description: "Silent looping clip shown inline like a GIF".to_string(),
+            profile: Some("main".to_string()),
+            max_level: Some(31),
+            tune: Some(Tune::Animation),
+            crf: 24,
+            bitrate: 1500,
+            fps: 30,
+            // Inline autoplay loops hitch on uneven frame timing
+            constant_frame_rate: true,
+            max_dimension: Some(1280),
+            audio: None,
+            ..base
+        },
+        "video-note" => Preset {
+            // Telegram shows video notes as a circle cut out of a square
+            // frame, so the center of the input is cropped out
+            description: "Round video message, square and at most 60 seconds".to_string(),
+            profile: Some("main".to_string()),
+            max_level: Some(31),
+            crf: 23,
+            bitrate: 1000,
+            fps: 30,
+            constant_frame_rate: true,
+            max_width: Some(640),
+            max_height: Some(640),
+            max_duration: Some(60.0),
+            square: true,
+            audio: aac(64, 48000),
+            ..base
+        },
+        "story" => Preset {
+            description: "Vertical story, at most 60 seconds".to_string(),
+            profile: Some("high".to_string()),
+            max_level: Some(40),
+            crf: 21,
+            bitrate: 4000,
+            fps: 30,
+            max_width: Some(720),
+            max_height: Some(1280),
+            max_duration: Some(60.0),
+            audio: aac(128, 48000),
+            ..base
+        },
+        "sticker" => Preset {
+            // Video stickers must have one side of exactly 512px, so small
+            // inputs are scaled up as well
+            description: "Video sticker, VP9 WebM of at most 3 seconds and 256KB".to_string(),
+            video_codec: VideoCodec::Vp9,
+            profile: None,
+            max_level: None,
+            pix_fmt: "yuva420p".to_string(),
+            // The size limit is met by retrying at a lower quality, so the
+            // bitrate isn't capped; CRF 30 for libvpx
+            rate_mode: RateMode::Crf,
+            crf: 22,
+            bitrate: 600,
+            fps: 30,
+            constant_frame_rate: true,
+            max_dimension: Some(512),
+            upscale: true,
+            max_duration: Some(3.0),
+            max_file_size: Some(256 * 1024),
+            audio: None,
+            container: Container::WebM,
+            ..base
+        },
+        _ => return None,
+    };
+    Some(preset)
+}
+
+/// The preset a mode flag such as `--animation` stands for.
+pub fn mode_preset(args: &Args) -> Option<&'static str> {
+    if args.video_note {
+        Some("video-note")
+    } else if args.sticker {
+        Some("sticker")
+    } else if args.animation {
+        Some("animation")
+    } else {
+        None
+    }
+}
+
+/// The preset selected by `--preset` (or a mode flag such as `--animation`),
+/// with the individual CLI flags on top.
+pub fn resolve(args: &Args) -> Result<Preset, String> {
+    let name = mode_preset(args)
+        .or(args.preset.as_deref())
+        .unwrap_or(DEFAULT_PRESET);
+    let mut preset = builtin(name).ok_or_else(|| {
+        format!(
+            "unknown preset '{}' (available: {})",
+            name,
+            BUILTIN_NAMES.join(", ")
+        )
+    })?;
+
+    // Telegram only takes VP9 stickers, so a codec from the config doesn't
+    // apply to them, and one from the command line is a mistake
+    if name == "sticker"
+        && let Some(codec) = args.codec
+        && !args.set_by_config("codec")
+    {
+        return Err(format!(
+            "--codec {} can't be used with the sticker preset, as Telegram only takes VP9 stickers",
+            codec.name()
+        ));
+    }
+    if let Some(codec) = args.codec
+        && codec != preset.video_codec
+        && name != "sticker"
+    {
+        if !codec.containers().contains(&preset.container) {
+            return Err(format!(
+                "{} can't be stored in {}, which the {} preset writes",
+                codec.label(),
+                preset.container.extension(),
+                preset.name
+            ));
+        }
+        preset.video_codec = codec;
+        // Profiles and levels are the H.264 ones
+        if codec != VideoCodec::H264 {
+            preset.profile = None;
+            preset.max_level = None;
+        }
+    }
+    if let Some(speed) = args.speed {
+        preset.speed = speed;
+    }
+    if let Some(tune) = args.tune {
+        if !preset.video_codec.supports_tune(tune) {
+            return Err(format!(
+                "{} has no {} tune",
+                preset.video_codec.encoder(),
+                tune.name()
+            ));
+        }
+        preset.tune = Some(tune);
+    }
+    if let Some(bitrate) = args.bitrate {
+        preset.bitrate = bitrate;
+    }
+    if let Some(fps_policy) = args.fps_policy {
+        preset.fps_policy = fps_policy;
+    }
+    if let Some(fps) = args.fps {
+        if fps == 0 {
+            return Err("--fps must be at least 1".to_string());
+        }
+        preset.fps = fps;
+    }
+    if let Some(crf) = args.crf {
+        preset.crf = crf;
+    }
+    if let Some(qp) = args.qp {
+        preset.qp = Some(qp);
+    }
+    if let Some(max_width) = args.max_width {
+        preset.max_width = Some(max_width);
+    }
+    if let Some(max_height) = args.max_height {
+        preset.max_height = Some(max_height);
+    }
+    if let Some(max_dimension) = args.max_dimension {
+        preset.max_dimension = Some(max_dimension);
+    }
+    // Sizes the user asks for are kept even past the level, which the
+    // level warning then points out
+    if args.max_width.is_some() || args.max_height.is_some() || args.max_dimension.is_some() {
+        preset.fit_level = false;
+    }
+    if args.no_upscale {
+        preset.upscale = false;
+    }
+    if let Some(audio_bitrate) = args.audio_bitrate
+        && let Some(audio) = &mut preset.audio
+    {
+        audio.bitrate = audio_bitrate;
+    }
+    preset.rate_mode = rate::mode(args, &preset)?;
+
+    Ok(preset)
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {}", self.name, self.description)?;
+        write!(f, "  Video: {}", self.video_codec.encoder())?;
+        if let Some(profile) = &self.profile {
+            write!(f, " {}", profile)?;
+        }
+        if let Some(level) = self.max_level {
+            write!(f, " up to level {}", h264::format_level(level))?;
+        }
+        write!(
+            f,
+            ", {}, {}",
+            self.pix_fmt,
+            RateControl::for_preset(self, None).describe(self.video_codec)
+        )?;
+        match self.fps_policy {
+            FpsPolicy::Keep => write!(f, ", source fps")?,
+            FpsPolicy::Cap => write!(f, ", up to {}fps", self.fps)?,
+            FpsPolicy::Force => write!(f, ", {}fps", self.fps)?,
+        }
+        if self.constant_frame_rate {
+            write!(f, " constant")?;
+        }
+        write!(f, ", {} speed", self.speed.name())?;
+        if let Some(tune) = self.tune {
+            write!(f, ", tune {}", tune.name())?;
+        }
+        writeln!(f)?;
+        if let (Some(w), Some(h)) = (self.max_width, self.max_height) {
+            writeln!(f, "  Max size: {}x{}", w, h)?;
+        }
+        if let Some(dimension) = self.max_dimension {
+            writeln!(
+                f,
+                "  Longest side: {}px{}",
+                dimension,
+                if self.upscale { ", scaled up" } else { "" }
+            )?;
+        }
+        if let Some(duration) = self.max_duration {
+            writeln!(f, "  Max duration: {}s", duration)?;
+        }
+        if let Some(size) = self.max_file_size {
+            writeln!(f, "  Max file size: {}", format_bytes(size))?;
+        }
+        writeln!(f, "  Container: {}", self.container.extension())?;
+        match &self.audio {
+            Some(a) => writeln!(
+                f,
+                "  Audio: {} {}kbps, {}Hz, {} channels",
+                a.codec, a.bitrate, a.sample_rate, a.channels
+            ),
+            None => writeln!(f, "  Audio: none"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn args(argv: &[&str]) -> Args {
+        let argv = ["telegram-video-converter", "in.mp4"].iter().chain(argv);
+        crate::Cli::try_parse_from(argv).unwrap().args
+    }
+
+    #[test]
+    fn rejects_a_command_line_codec_for_stickers() {
+        let err = resolve(&args(&["--preset", "sticker", "--codec", "h264"]))
+            .err()
+            .unwrap();
+        assert!(err.starts_with("--codec h264 can't be used"), "{}", err);
+    }
+
+    #[test]
+    fn keeps_stickers_in_vp9_despite_the_config() {
+        let mut args = args(&["--preset", "sticker", "--codec", "h264"]);
+        args.config_keys.insert("codec".to_string());
+        let preset = resolve(&args).unwrap();
+        assert_eq!(preset.video_codec, VideoCodec::Vp9);
+    }
+}
@@ -0,0 +1,76 @@
+//! Run conversions at a low CPU and IO priority, so the machine stays
+//! responsive while ffmpeg works.
+//!
+//! The priority is lowered for the converter itself, and ffmpeg inherits it.
+
+use std::ffi::c_int;
+#[cfg(target_os = "linux")]
+use std::ffi::c_long;
+use std::io;
+
+/// Nice value; 19 is the lowest priority.
+const NICE: c_int = 10;
+
+const PRIO_PROCESS: c_int = 0;
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: c_int = 1;
+/// Idle class: the disk is only used when nothing else needs it
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_IDLE: c_int = 3;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_SHIFT: c_int = 13;
+
+/// `ioprio_set` is Linux only and has no libc wrapper, so it is called by
+/// number, which differs between architectures.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const SYS_IOPRIO_SET: Option<c_long> = Some(251);
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "aarch64", target_arch = "riscv64")
+))]
+const SYS_IOPRIO_SET: Option<c_long> = Some(30);
+#[cfg(all(
+    target_os = "linux",
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))
+))]
+const SYS_IOPRIO_SET: Option<c_long> = None;
+
+unsafe extern "C" {
+    fn setpriority(which: c_int, who: c_int, prio: c_int) -> c_int;
+}
+
+#[cfg(target_os = "linux")]
+unsafe extern "C" {
+    fn syscall(number: c_long, ...) -> c_long;
+}
+
+/// Lower the CPU and IO priority of this process and the ones it starts.
+pub fn lower() -> io::Result<()> {
+    // SAFETY: plain syscall on the calling process
+    if unsafe { setpriority(PRIO_PROCESS, 0, NICE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    lower_io()
+}
+
+#[cfg(target_os = "linux")]
+fn lower_io() -> io::Result<()> {
+    if let Some(number) = SYS_IOPRIO_SET {
+        let class = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+        // SAFETY: ioprio_set on the calling process, by its Linux number
+        if unsafe { syscall(number, IOPRIO_WHO_PROCESS, 0 as c_int, class) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Other systems only get the lower CPU priority.
+#[cfg(not(target_os = "linux"))]
+fn lower_io() -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,633 @@
+//! Input inspection through ffprobe.
+
+use crate::format_bytes;
+use crate::h264;
+use crate::json::Json;
+use std::fmt;
+use std::process::Command;
+
+/// Everything we know about an input file, as reported by ffprobe.
+#[derive(Debug, Clone)]
+pub struct MediaInfo {
+    pub format_name: String,
+    /// Container duration in seconds
+    pub duration: Option<f64>,
+    pub size: Option<u64>,
+    /// Overall bitrate in bits per second
+    pub bit_rate: Option<u64>,
+    /// What the container and stream tags say about the program that
+    /// wrote the file
+    pub software: Vec<String>,
+    pub streams: Vec<Stream>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Stream {
+    Video(VideoStream),
+    Audio(AudioStream),
+    Other {
+        index: usize,
+        codec_type: String,
+        codec_name: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct VideoStream {
+    pub index: usize,
+    pub codec_name: String,
+    pub profile: Option<String>,
+    /// H.264 style level multiplied by ten (e.g. 31 for level 3.1)
+    pub level: Option<u32>,
+    pub width: u32,
+    pub height: u32,
+    /// Nominal frame rate (`r_frame_rate`)
+    pub frame_rate: Option<f64>,
+    /// Average frame rate over the whole stream (`avg_frame_rate`)
+    pub avg_frame_rate: Option<f64>,
+    pub pix_fmt: Option<String>,
+    /// Whether the video has transparency, either in its pixel format or,
+    /// for VP8/VP9, in a separate alpha stream
+    pub alpha: bool,
+    pub color: ColorInfo,
+    /// Display rotation in degrees, normalized to 0, 90, 180 or 270
+    pub rotation: u32,
+    pub bit_rate: Option<u64>,
+    pub duration: Option<f64>,
+    pub frame_count: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ColorInfo {
+    pub range: Option<String>,
+    pub space: Option<String>,
+    pub transfer: Option<String>,
+    pub primaries: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioStream {
+    pub index: usize,
+    pub codec_name: String,
+    pub profile: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub channels: u32,
+    pub channel_layout: Option<String>,
+    pub bit_rate: Option<u64>,
+}
+
+impl VideoStream {
+    /// Width and height the way the video is shown, after rotation.
+    pub fn display_size(&self) -> (u32, u32) {
+        if self.rotation % 180 == 90 {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        }
+    }
+
+    /// Whether frames come at irregular intervals, as in many screen and
+    /// phone recordings: the nominal rate is off the average.
+    pub fn is_vfr(&self) -> bool {
+        match (self.frame_rate, self.avg_frame_rate) {
+            (Some(fps), Some(avg)) => (fps - avg).abs() > fps * 0.02,
+            _ => false,
+        }
+    }
+
+    /// Decoder needed to read the alpha channel; ffmpeg's native VP8/VP9
+    /// decoders silently drop it.
+    pub fn alpha_decoder(&self) -> Option<&'static str> {
+        match self.codec_name.as_str() {
+            "vp8" if self.alpha => Some("libvpx"),
+            "vp9" if self.alpha => Some("libvpx-vp9"),
+            _ => None,
+        }
+    }
+}
+
+impl MediaInfo {
+    pub fn video(&self) -> Option<&VideoStream> {
+        self.streams.iter().find_map(|s| match s {
+            Stream::Video(v) => Some(v),
+            _ => None,
+        })
+    }
+
+    pub fn audio(&self) -> Option<&AudioStream> {
+        self.streams.iter().find_map(|s| match s {
+            Stream::Audio(a) => Some(a),
+            _ => None,
+        })
+    }
+
+    /// Best known duration: the container's, falling back to the video stream's.
+    pub fn duration(&self) -> Option<f64> {
+        self.duration
+            .or_else(|| self.video().and_then(|v| v.duration))
+    }
+
+    pub fn from_json(json: &Json) -> Result<MediaInfo, String> {
+        let format = json
+            .get("format")
+            .ok_or("ffprobe output has no format section")?;
+
+        let stream_list = json.get("streams").map(Json::as_array).unwrap_or_default();
+        let streams = stream_list.iter().map(parse_stream).collect();
+
+        let mut software = software_tags(format);
+        for stream in stream_list {
+            for tag in software_tags(stream) {
+                if !software.contains(&tag) {
+                    software.push(tag);
+                }
+            }
+        }
+
+        Ok(MediaInfo {
+            format_name: string(format, "format_name").unwrap_or_default(),
+            duration: format.get("duration").and_then(Json::as_f64),
+            size: format.get("size").and_then(Json::as_u64),
+            bit_rate: format.get("bit_rate").and_then(Json::as_u64),
+            software,
+            streams,
+        })
+    }
+}
+
+/// Run ffprobe on `path` and parse its report.
+pub fn probe(path: &str) -> Result<MediaInfo, String> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+            path,
+        ])
+        .output()
+        .map_err(|e| format!("failed to execute ffprobe: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "ffprobe could not read '{}': {}",
+            path,
+            stderr.trim()
+        ));
+    }
+
+    let json = Json::parse(&String::from_utf8_lossy(&output.stdout))?;
+    MediaInfo::from_json(&json)
+}
+
+pub fn is_ffprobe_available() -> bool {
+    Command::new("ffprobe").arg("-version").output().is_ok()
+}
+
+fn string(json: &Json, key: &str) -> Option<String> {
+    json.get(key)
+        .and_then(Json::as_str)
+        .filter(|s| !s.is_empty() && *s != "unknown")
+        .map(str::to_string)
+}
+
+/// Tags that name the program that wrote a file.
+const SOFTWARE_TAGS: &[&str] = &[
+    "encoder",
+    "handler_name",
+    "comment",
+    "software",
+    "com.apple.quicktime.software",
+];
+
+/// Values of the software tags of a format or stream; Matroska writes tag
+/// names in upper case.
+fn software_tags(json: &Json) -> Vec<String> {
+    let Some(Json::Object(tags)) = json.get("tags") else {
+        return Vec::new();
+    };
+    tags.iter()
+        .filter(|(key, _)| SOFTWARE_TAGS.contains(&key.to_ascii_lowercase().as_str()))
+        .filter_map(|(_, value)| value.as_str())
+        // ffmpeg's generic VideoHandler and SoundHandler say nothing
+        .filter(|value| !value.is_empty() && !value.ends_with("Handler"))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parse ffprobe rationals such as `30000/1001`; `0/0` means unknown.
+fn rational(json: &Json, key: &str) -> Option<f64> {
+    let text = json.get(key)?.as_str()?;
+    let (num, den) = text.split_once('/').unwrap_or((text, "1"));
+    let num: f64 = num.parse().ok()?;
+    let den: f64 = den.parse().ok()?;
+    (num > 0.0 && den > 0.0).then(|| num / den)
+}
+
+fn parse_stream(json: &Json) -> Stream {
+    let index = json.get("index").and_then(Json::as_u64).unwrap_or(0) as usize;
+    let codec_type = string(json, "codec_type").unwrap_or_default();
+    let codec_name = string(json, "codec_name").unwrap_or_default();
+
+    match codec_type.as_str() {
+        "video" => {
+            let pix_fmt = string(json, "pix_fmt");
+            let alpha = pix_fmt.as_deref().is_some_and(has_alpha)
+                || json
+                    .get("tags")
+                    .and_then(|t| t.get("alpha_mode"))
+                    .and_then(Json::as_str)
+                    == Some("1");
+            Stream::Video(VideoStream {
+                index,
+                codec_name,
+                profile: string(json, "profile"),
+                level: json
+                    .get("level")
+                    .and_then(Json::as_f64)
+                    .filter(|l| *l > 0.0)
+                    .map(|l| l as u32),
+                width: json.get("width").and_then(Json::as_u64).unwrap_or(0) as u32,
+                height: json.get("height").and_then(Json::as_u64).unwrap_or(0) as u32,
+                frame_rate: rational(json, "r_frame_rate"),
+                avg_frame_rate: rational(json, "avg_frame_rate"),
+                pix_fmt,
+                alpha,
+                color: ColorInfo {
+                    range: string(json, "color_range"),
+                    space: string(json, "color_space"),
+                    transfer: string(json, "color_transfer"),
+                    primaries: string(json, "color_primaries"),
+                },
+                rotation: rotation(json),
+                bit_rate: json.get("bit_rate").and_then(Json::as_u64),
+                duration: json.get("duration").and_then(Json::as_f64),
+                frame_count: json.get("nb_frames").and_then(Json::as_u64),
+            })
+        }
+        "audio" => Stream::Audio(AudioStream {
+            index,
+            codec_name,
+            profile: string(json, "profile"),
+            sample_rate: json
+                .get("sample_rate")
+                .and_then(Json::as_u64)
+                .map(|r| r as u32),
+            channels: json.get("channels").and_then(Json::as_u64).unwrap_or(0) as u32,
+            channel_layout: string(json, "channel_layout"),
+            bit_rate: json.get("bit_rate").and_then(Json::as_u64),
+        }),
+        _ => Stream::Other {
+            index,
+            codec_type,
+            codec_name,
+        },
+    }
+}
+
+/// Pixel formats with an alpha plane, such as `yuva420p`, `rgba` or `gbrap`.
+fn has_alpha(pix_fmt: &str) -> bool {
+    pix_fmt.starts_with("yuva")
+        || pix_fmt.starts_with("ya")
+        || pix_fmt.starts_with("gbrap")
+        || ["rgba", "bgra", "argb", "abgr"]
+            .iter()
+            .any(|f| pix_fmt.starts_with(f))
+}
+
+/// Rotation from the display matrix side data (newer ffmpeg) or the
+/// legacy `rotate` tag (older ffmpeg). The display matrix stores the
+/// counter-clockwise angle, so it is flipped to match the tag's convention.
+fn rotation(json: &Json) -> u32 {
+    let from_side_data = json
+        .get("side_data_list")
+        .map(Json::as_array)
+        .unwrap_or_default()
+        .iter()
+        .find_map(|sd| sd.get("rotation").and_then(Json::as_f64))
+        .map(|r| -r);
+    let from_tag = || {
+        json.get("tags")
+            .and_then(|t| t.get("rotate"))
+            .and_then(Json::as_f64)
+    };
+
+    let degrees = from_side_data.or_else(from_tag).unwrap_or(0.0);
+    ((degrees.round() as i64).rem_euclid(360) as u32 + 45) / 90 % 4 * 90
+}
+
+impl fmt::Display for MediaInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Format: {}", self.format_name)?;
+        if let Some(duration) = self.duration() {
+            writeln!(f, "Duration: {:.2}s", duration)?;
+        }
+        if let Some(size) = self.size {
+            writeln!(f, "Size: {}", format_bytes(size))?;
+        }
+        if let Some(bit_rate) = self.bit_rate {
+            writeln!(f, "Bitrate: {}kbps", bit_rate / 1000)?;
+        }
+        if !self.software.is_empty() {
+            writeln!(f, "Written by: {}", self.software.join(", "))?;
+        }
+
+        for stream in &self.streams {
+            match stream {
+                Stream::Video(v) => {
+                    write!(f, "Stream #{}: video {}", v.index, v.codec_name)?;
+                    if let Some(profile) = &v.profile {
+                        write!(f, " ({})", profile)?;
+                    }
+                    // Other codecs count their levels differently (HEVC
+                    // reports 120 for level 4)
+                    if let Some(level) = v.level
+                        && v.codec_name == "h264"
+                    {
+                        write!(f, " level {}", h264::format_level(level))?;
+                    }
+                    writeln!(f)?;
+                    writeln!(f, "  Resolution: {}x{}", v.width, v.height)?;
+                    if let Some(fps) = v.frame_rate {
+                        write!(f, "  Frame rate: {:.3}fps", fps)?;
+                        match v.avg_frame_rate {
+                            Some(avg) if (avg - fps).abs() > 0.01 => {
+                                writeln!(f, " (average {:.3}fps)", avg)?
+                            }
+                            _ => writeln!(f)?,
+                        }
+                    }
+                    if let Some(frames) = v.frame_count {
+                        writeln!(f, "  Frames: {}", frames)?;
+                    }
+                    if let Some(pix_fmt) = &v.pix_fmt {
+                        writeln!(f, "  Pixel format: {}", pix_fmt)?;
+                    }
+                    if v.alpha {
+                        writeln!(f, "  Transparency: yes")?;
+                    }
+                    let c = &v.color;
+                    if c.range.is_some() || c.space.is_some() {
+                        writeln!(
+                            f,
+                            "  Color: range {}, space {}, transfer {}, primaries {}",
+                            c.range.as_deref().unwrap_or("?"),
+                            c.space.as_deref().unwrap_or("?"),
+                            c.transfer.as_deref().unwrap_or("?"),
+                            c.primaries.as_deref().unwrap_or("?"),
+                        )?;
+                    }
+                    if v.rotation != 0 {
+                        writeln!(f, "  Rotation: {}°", v.rotation)?;
+                    }
+                    if let Some(bit_rate) = v.bit_rate {
+                        writeln!(f, "  Bitrate: {}kbps", bit_rate / 1000)?;
+                    }
+                }
+                Stream::Audio(a) => {
+                    write!(f, "Stream #{}: audio {}", a.index, a.codec_name)?;
+                    if let Some(profile) = &a.profile {
+                        write!(f, " ({})", profile)?;
+                    }
+                    writeln!(f)?;
+                    write!(f, "  Channels: {}", a.channels)?;
+                    match &a.channel_layout {
+                        Some(layout) => writeln!(f, " ({})", layout)?,
+                        None => writeln!(f)?,
+                    }
+                    if let Some(rate) = a.sample_rate {
+                        writeln!(f, "  Sample rate: {}Hz", rate)?;
+                    }
+                    if let Some(bit_rate) = a.bit_rate {
+                        writeln!(f, "  Bitrate: {}kbps", bit_rate / 1000)?;
+                    }
+                }
+                Stream::Other {
+                    index,
+                    codec_type,
+                    codec_name,
+                } => {
+                    writeln!(f, "Stream #{}: {} {}", index, codec_type, codec_name)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `ffprobe -show_format -show_streams` of an iPhone clip recorded
+    /// upright, trimmed to the fields that matter.
+    const PHONE_CLIP: &str = r#"{
+    "streams": [
+        {
+            "index": 0,
+            "codec_name": "hevc",
+            "profile": "Main 10",
+            "codec_type": "video",
+            "width": 1920,
+            "height": 1080,
+            "pix_fmt": "yuv420p10le",
+            "level": 120,
+            "color_range": "tv",
+            "color_space": "bt2020nc",
+            "color_transfer": "arib-std-b67",
+            "color_primaries": "bt2020",
+            "r_frame_rate": "30/1",
+            "avg_frame_rate": "16200/541",
+            "duration": "18.033333",
+            "bit_rate": "9953162",
+            "nb_frames": "541",
+            "tags": {
+                "creation_time": "2024-05-01T10:00:00.000000Z",
+                "handler_name": "Core Media Video",
+                "encoder": "HEVC"
+            },
+            "side_data_list": [
+                {
+                    "side_data_type": "Display Matrix",
+                    "displaymatrix": "\n00000000:            0       65536           0\n",
+                    "rotation": -90
+                }
+            ]
+        },
+        {
+            "index": 1,
+            "codec_name": "aac",
+            "profile": "LC",
+            "codec_type": "audio",
+            "sample_rate": "44100",
+            "channels": 2,
+            "channel_layout": "stereo",
+            "bit_rate": "188639",
+            "tags": {
+                "handler_name": "Core Media Audio"
+            }
+        },
+        {
+            "index": 2,
+            "codec_name": "unknown",
+            "codec_type": "data",
+            "r_frame_rate": "0/0",
+            "avg_frame_rate": "0/0",
+            "tags": {
+                "handler_name": "Core Media Metadata"
+            }
+        }
+    ],
+    "format": {
+        "filename": "IMG_0001.MOV",
+        "nb_streams": 3,
+        "format_name": "mov,mp4,m4a,3gp,3g2,mj2",
+        "duration": "18.033333",
+        "size": "22869418",
+        "bit_rate": "10145470",
+        "tags": {
+            "major_brand": "qt  ",
+            "com.apple.quicktime.software": "17.4.1"
+        }
+    }
+}"#;
+
+    /// A transparent VP9 WebM as ffmpeg writes it: the pixel format has no
+    /// alpha, only the `alpha_mode` tag tells.
+    const ALPHA_WEBM: &str = r#"{
+    "streams": [
+        {
+            "index": 0,
+            "codec_name": "vp9",
+            "codec_type": "video",
+            "width": 512,
+            "height": 512,
+            "pix_fmt": "yuv420p",
+            "level": -99,
+            "r_frame_rate": "30/1",
+            "avg_frame_rate": "30/1",
+            "tags": {
+                "alpha_mode": "1",
+                "ENCODER": "Lavc60.3.100 libvpx-vp9",
+                "DURATION": "00:00:02.966000000"
+            }
+        }
+    ],
+    "format": {
+        "format_name": "matroska,webm",
+        "duration": "2.966000",
+        "size": "91731",
+        "bit_rate": "247420",
+        "tags": {
+            "ENCODER": "Lavf60.3.100"
+        }
+    }
+}"#;
+
+    fn parse(text: &str) -> MediaInfo {
+        MediaInfo::from_json(&Json::parse(text).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn reads_a_phone_clip() {
+        let media = parse(PHONE_CLIP);
+        assert_eq!(media.format_name, "mov,mp4,m4a,3gp,3g2,mj2");
+        assert_eq!(media.duration(), Some(18.033333));
+        assert_eq!(media.size, Some(22_869_418));
+        assert_eq!(media.bit_rate, Some(10_145_470));
+        assert_eq!(
+            media.software,
+            [
+                "17.4.1",
+                "HEVC",
+                "Core Media Video",
+                "Core Media Audio",
+                "Core Media Metadata"
+            ]
+        );
+
+        let video = media.video().unwrap();
+        assert_eq!(video.codec_name, "hevc");
+        assert_eq!(video.level, Some(120));
+        assert_eq!((video.width, video.height), (1920, 1080));
+        // The display matrix turns counter-clockwise, the rotation is clockwise
+        assert_eq!(video.rotation, 90);
+        assert_eq!(video.display_size(), (1080, 1920));
+        assert_eq!(video.frame_rate, Some(30.0));
+        assert!((video.avg_frame_rate.unwrap() - 29.945).abs() < 0.001);
+        assert!(!video.is_vfr());
+        assert_eq!(video.bit_rate, Some(9_953_162));
+        assert_eq!(video.frame_count, Some(541));
+        assert_eq!(video.color.transfer.as_deref(), Some("arib-std-b67"));
+        assert!(!video.alpha);
+
+        let audio = media.audio().unwrap();
+        assert_eq!(audio.sample_rate, Some(44100));
+        assert_eq!(audio.channels, 2);
+        assert_eq!(audio.bit_rate, Some(188_639));
+
+        // `0/0` rates and an `unknown` codec mean nothing is known
+        match &media.streams[2] {
+            Stream::Other {
+                codec_type,
+                codec_name,
+                ..
+            } => assert_eq!((codec_type.as_str(), codec_name.as_str()), ("data", "")),
+            other => panic!("expected a data stream, got {:?}", other),
+        }
+        assert_eq!(
+            rational(&Json::parse(r#"{"r": "0/0"}"#).unwrap(), "r"),
+            None
+        );
+    }
+
+    #[test]
+    fn reads_transparency_from_the_alpha_mode_tag() {
+        let media = parse(ALPHA_WEBM);
+        let video = media.video().unwrap();
+        assert!(video.alpha);
+        assert_eq!(video.alpha_decoder(), Some("libvpx-vp9"));
+        assert_eq!(video.level, None);
+        assert_eq!(video.rotation, 0);
+        assert_eq!(media.software, ["Lavf60.3.100", "Lavc60.3.100 libvpx-vp9"]);
+        assert!(media.audio().is_none());
+    }
+
+    #[test]
+    fn normalizes_rotations() {
+        let rotation_of = |json: &str| rotation(&Json::parse(json).unwrap());
+        assert_eq!(
+            rotation_of(r#"{"side_data_list": [{"rotation": 90}]}"#),
+            270
+        );
+        assert_eq!(
+            rotation_of(r#"{"side_data_list": [{"rotation": -180}]}"#),
+            180
+        );
+        assert_eq!(rotation_of(r#"{"tags": {"rotate": "90"}}"#), 90);
+        assert_eq!(rotation_of(r#"{"tags": {"rotate": "-90"}}"#), 270);
+        assert_eq!(
+            rotation_of(r#"{"side_data_list": [{"rotation": -89.9}]}"#),
+            90
+        );
+        assert_eq!(rotation_of("{}"), 0);
+    }
+
+    #[test]
+    fn shows_levels_only_for_h264() {
+        let hevc = parse(PHONE_CLIP).to_string();
+        assert!(hevc.contains("video hevc (Main 10)\n"), "{}", hevc);
+
+        let h264 = PHONE_CLIP
+            .replace(r#""codec_name": "hevc""#, r#""codec_name": "h264""#)
+            .replace(r#""level": 120"#, r#""level": 31"#);
+        assert!(
+            parse(&h264)
+                .to_string()
+                .contains("video h264 (Main 10) level 3.1\n")
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Follow ffmpeg's `-progress` output and report it to the user.
+
+use crate::format_bytes;
+use crate::scheduler::Job;
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 30;
+
+/// How often a plain progress line is printed when stdout is not a terminal.
+const PLAIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Latest values from one `-progress` block.
+#[derive(Default)]
+struct Update {
+    /// Output timestamp in seconds
+    out_time: f64,
+    /// Output size in bytes so far
+    total_size: u64,
+    /// Encode speed relative to realtime
+    speed: Option<f64>,
+}
+
+pub struct Progress<'a> {
+    job: &'a Job<'a>,
+    label: String,
+    duration: Option<f64>,
+    tty: bool,
+    started: Instant,
+    last_print: Option<Instant>,
+}
+
+impl<'a> Progress<'a> {
+    pub fn new(label: &str, duration: Option<f64>, job: &'a Job<'a>) -> Progress<'a> {
+        Progress {
+            job,
+            label: label.to_string(),
+            duration: duration.filter(|d| *d > 0.0),
+            // Concurrent jobs can't share one line, so they get plain lines
+            tty: std::io::stdout().is_terminal() && !job.is_concurrent(),
+            started: Instant::now(),
+            last_print: None,
+        }
+    }
+
+    fn update(&mut self, update: &Update) {
+        let now = Instant::now();
+        if !self.tty
+            && self
+                .last_print
+                .is_some_and(|last| now - last < PLAIN_INTERVAL)
+        {
+            return;
+        }
+        self.last_print = Some(now);
+
+        let fraction = self.duration.map(|d| (update.out_time / d).clamp(0.0, 1.0));
+
+        let mut line = String::new();
+        if self.tty {
+            let filled = fraction.map_or(0, |f| (f * BAR_WIDTH as f64) as usize);
+            line.push_str(&format!(
+                "[{}{}]",
+                "#".repeat(filled),
+                "-".repeat(BAR_WIDTH - filled)
+            ));
+        }
+        match fraction {
+            Some(f) => line.push_str(&format!(" {:5.1}%", f * 100.0)),
+            None => line.push_str(&format!(" {}", format_clock(update.out_time))),
+        }
+        if let Some(speed) = update.speed {
+            line.push_str(&format!("  {:.2}x", speed));
+        }
+        if update.total_size > 0 {
+            line.push_str(&format!("  {}", format_bytes(update.total_size)));
+        }
+        if let Some(eta) = self.eta(update.out_time) {
+            line.push_str(&format!("  ETA {}", format_clock(eta)));
+        }
+
+        if self.tty {
+            print!("\r  {}:{}\x1b[K", self.label, line);
+            let _ = std::io::stdout().flush();
+        } else {
+            self.job.say(format!("  {}:{}", self.label, line));
+        }
+    }
+
+    /// Remaining seconds, extrapolated from the progress so far.
+    fn eta(&self, out_time: f64) -> Option<f64> {
+        let duration = self.duration?;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if out_time <= 0.0 || elapsed < 1.0 {
+            return None;
+        }
+        Some((duration - out_time).max(0.0) * elapsed / out_time)
+    }
+
+    fn finish(&self) {
+        if self.tty && self.last_print.is_some() {
+            println!();
+        }
+    }
+
+    /// Run `cmd` (which must write `-progress pipe:1`) while showing its progress.
+    pub fn run(mut self, cmd: &mut Command) -> std::io::Result<ExitStatus> {
+        let mut child = cmd.stdout(Stdio::piped()).spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        let mut update = Update::default();
+        for line in BufReader::new(stdout).lines() {
+            let line = line?;
+
+            // Stop the encode when another job failed under --fail-fast
+            if self.job.is_cancelled() {
+                let _ = child.kill();
+                break;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match key {
+                // Despite the name, out_time_ms is in microseconds too
+                "out_time_us" | "out_time_ms" => {
+                    if let Ok(us) = value.parse::<i64>() {
+                        update.out_time = us.max(0) as f64 / 1_000_000.0;
+                    }
+                }
+                "total_size" => update.total_size = value.parse().unwrap_or(0),
+                "speed" => update.speed = value.trim_end_matches('x').trim().parse().ok(),
+                "progress" => {
+                    // Always show the final state, even between plain lines
+                    if value == "end" {
+                        update.out_time = self.duration.unwrap_or(update.out_time);
+                        self.last_print = None;
+                    }
+                    self.update(&update);
+                }
+                _ => {}
+            }
+        }
+
+        self.finish();
+        child.wait()
+    }
+}
+
+/// Format seconds as `MM:SS` or `H:MM:SS`.
+fn format_clock(seconds: f64) -> String {
+    let total = seconds.round() as u64;
+    let (h, m, s) = (total / 3600, total / 60 % 60, total % 60);
+    if h > 0 {
+        format!("{}:{:02}:{:02}", h, m, s)
+    } else {
+        format!("{:02}:{:02}", m, s)
+    }
+}
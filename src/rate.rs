@@ -0,0 +1,351 @@
+//! How the encoder spends bits: constant quality, a bitrate, or a fixed
+//! quantizer.
+
+use crate::Args;
+use crate::codec::{MAX_CRF, VideoCodec};
+use crate::preset::Preset;
+
+/// Rate control mode, as chosen with `--rate-control`.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum RateMode {
+    /// Constant quality; the bitrate follows the content
+    Crf,
+    /// Constant quality, but never above --bitrate
+    CappedCrf,
+    /// Average bitrate of --bitrate; with --two-pass or --target-size it is
+    /// hit accurately
+    Abr,
+    /// Constant bitrate of --bitrate
+    Cbr,
+    /// Constant quantizer --qp, mostly for testing
+    Qp,
+}
+
+impl RateMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            RateMode::Crf => "crf",
+            RateMode::CappedCrf => "capped-crf",
+            RateMode::Abr => "abr",
+            RateMode::Cbr => "cbr",
+            RateMode::Qp => "qp",
+        }
+    }
+}
+
+/// Rate control of one encode, with the values it runs at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateControl {
+    /// CRF on the libx264 scale
+    Crf(u32),
+    CappedCrf {
+        crf: u32,
+        max_bitrate: u32,
+    },
+    /// Average bitrate in kbps
+    Abr(u32),
+    /// Constant bitrate in kbps
+    Cbr(u32),
+    Qp(u32),
+}
+
+/// Which encoders run in each mode. SVT-AV1 only does CBR in low-delay
+/// mode and has no constant quantizer; libvpx and libaom have no constant
+/// quantizer either.
+fn supports(codec: VideoCodec, mode: RateMode) -> bool {
+    match mode {
+        RateMode::Crf | RateMode::CappedCrf | RateMode::Abr => true,
+        RateMode::Cbr => codec != VideoCodec::Av1,
+        RateMode::Qp => matches!(codec, VideoCodec::H264 | VideoCodec::H265),
+    }
+}
+
+/// The rate control mode `args` ask for, checking that the options given
+/// for it make sense. Without `--rate-control` the mode follows from the
+/// options. Options for other modes are an error on the command line, and
+/// ignored when they come from the config.
+pub fn mode(args: &Args, preset: &Preset) -> Result<RateMode, String> {
+    if let Some(crf) = args.crf
+        && crf > MAX_CRF
+    {
+        return Err(format!("--crf must be at most {}, not {}", MAX_CRF, crf));
+    }
+    if let Some(qp) = args.qp
+        && qp > MAX_CRF
+    {
+        return Err(format!("--qp must be at most {}, not {}", MAX_CRF, qp));
+    }
+    if args.bitrate == Some(0) {
+        return Err("--bitrate must be at least 1".to_string());
+    }
+
+    // Whether `key` is set, on the command line or in the config
+    let given = |key: &str, set: bool, on_command_line: bool| {
+        set && args.set_by_config(key) != on_command_line
+    };
+    let implied = |on_command_line: bool| {
+        if given("qp", args.qp.is_some(), on_command_line) {
+            Some(RateMode::Qp)
+        } else if given("target_size", args.target_size.is_some(), on_command_line)
+            || given("two_pass", args.two_pass, on_command_line)
+        {
+            // Hitting a size or running two passes needs an average bitrate
+            Some(RateMode::Abr)
+        } else {
+            None
+        }
+    };
+    // The config mustn't pick a mode that the command line's --crf or
+    // --bitrate would have no effect in
+    let command_line_rate =
+        given("crf", args.crf.is_some(), true) || given("bitrate", args.bitrate.is_some(), true);
+    let mode = args
+        .rate_control
+        .or_else(|| implied(true))
+        .or_else(|| implied(false).filter(|_| !command_line_rate))
+        .unwrap_or(preset.rate_mode);
+
+    let unused = |set: bool, key: &str| {
+        if !given(key, set, true) {
+            return Ok(());
+        }
+        let option = format!("--{}", key.replace('_', "-"));
+        Err(match args.rate_control {
+            Some(_) if args.set_by_config("rate_control") => format!(
+                "{} has no effect with the config's {} rate control",
+                option,
+                mode.name()
+            ),
+            Some(_) => format!(
+                "{} has no effect with --rate-control {}",
+                option,
+                mode.name()
+            ),
+            None => format!(
+                "{} has no effect with {} rate control; choose another with --rate-control",
+                option,
+                mode.name()
+            ),
+        })
+    };
+    let bitrate_modes = matches!(mode, RateMode::Abr | RateMode::Cbr);
+    unused(
+        matches!(mode, RateMode::Crf | RateMode::Qp) && args.bitrate.is_some(),
+        "bitrate",
+    )?;
+    unused(
+        matches!(mode, RateMode::Abr | RateMode::Cbr | RateMode::Qp) && args.crf.is_some(),
+        "crf",
+    )?;
+    unused(mode != RateMode::Qp && args.qp.is_some(), "qp")?;
+    unused(!bitrate_modes && args.target_size.is_some(), "target_size")?;
+    unused(mode != RateMode::Abr && args.two_pass, "two_pass")?;
+
+    if !supports(preset.video_codec, mode) {
+        return Err(format!(
+            "{} has no {} rate control",
+            preset.video_codec.encoder(),
+            mode.name()
+        ));
+    }
+    Ok(mode)
+}
+
+/// Drop the settings from the config that `mode` has no use for, so that
+/// a `target_size` or `two_pass` meant for other modes doesn't apply.
+pub fn drop_unused(args: &mut Args, mode: RateMode) {
+    if !matches!(mode, RateMode::Abr | RateMode::Cbr) {
+        args.target_size = None;
+    }
+    if mode != RateMode::Abr {
+        args.two_pass = false;
+    }
+}
+
+impl RateControl {
+    /// The rate control of `preset`, at `bitrate` kbps instead of the
+    /// preset's bitrate when set (from `--target-size`).
+    pub fn for_preset(preset: &Preset, bitrate: Option<u32>) -> RateControl {
+        let bitrate = bitrate.unwrap_or(preset.bitrate);
+        match preset.rate_mode {
+            RateMode::Crf => RateControl::Crf(preset.crf),
+            RateMode::CappedCrf => RateControl::CappedCrf {
+                crf: preset.crf,
+                max_bitrate: bitrate,
+            },
+            RateMode::Abr => RateControl::Abr(bitrate),
+            RateMode::Cbr => RateControl::Cbr(bitrate),
+            RateMode::Qp => RateControl::Qp(preset.qp.unwrap_or(preset.crf)),
+        }
+    }
+
+    /// Highest bitrate in kbps the mode holds the video to, if any.
+    pub fn max_bitrate(self) -> Option<u32> {
+        match self {
+            RateControl::CappedCrf { max_bitrate, .. } => Some(max_bitrate),
+            RateControl::Abr(bitrate) | RateControl::Cbr(bitrate) => Some(bitrate),
+            RateControl::Crf(_) | RateControl::Qp(_) => None,
+        }
+    }
+
+    /// ffmpeg options for the mode with `codec`.
+    pub fn args(self, codec: VideoCodec) -> Vec<String> {
+        let kbps = |bitrate: u32| format!("{}k", bitrate);
+        let args = |list: &[&str]| list.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let x26x = matches!(codec, VideoCodec::H264 | VideoCodec::H265);
+        match self {
+            RateControl::Crf(crf) if x26x || codec == VideoCodec::Av1 => {
+                args(&["-crf", &codec.crf(crf).to_string()])
+            }
+            // libvpx and libaom only run in constant quality mode with a
+            // zero bitrate
+            RateControl::Crf(crf) => args(&["-crf", &codec.crf(crf).to_string(), "-b:v", "0"]),
+            RateControl::CappedCrf { crf, max_bitrate } if x26x => args(&[
+                "-crf",
+                &codec.crf(crf).to_string(),
+                "-maxrate",
+                &kbps(max_bitrate),
+                "-bufsize",
+                &kbps(max_bitrate * 2),
+            ]),
+            RateControl::CappedCrf { crf, max_bitrate } if codec == VideoCodec::Av1 => args(&[
+                "-crf",
+                &codec.crf(crf).to_string(),
+                "-maxrate",
+                &kbps(max_bitrate),
+            ]),
+            // Constrained quality: the bitrate is the ceiling
+            RateControl::CappedCrf { crf, max_bitrate } => args(&[
+                "-crf",
+                &codec.crf(crf).to_string(),
+                "-b:v",
+                &kbps(max_bitrate),
+            ]),
+            RateControl::Abr(bitrate) => args(&["-b:v", &kbps(bitrate)]),
+            // libx265 has no minimum rate; a full buffer at the target
+            // rate is its CBR
+            RateControl::Cbr(bitrate) if codec == VideoCodec::H265 => args(&[
+                "-b:v",
+                &kbps(bitrate),
+                "-maxrate",
+                &kbps(bitrate),
+                "-bufsize",
+                &kbps(bitrate),
+            ]),
+            RateControl::Cbr(bitrate) => args(&[
+                "-b:v",
+                &kbps(bitrate),
+                "-minrate",
+                &kbps(bitrate),
+                "-maxrate",
+                &kbps(bitrate),
+                "-bufsize",
+                &kbps(bitrate),
+            ]),
+            RateControl::Qp(qp) => args(&["-qp", &qp.to_string()]),
+        }
+    }
+
+    /// The mode for messages, with the CRF `codec` is given; when that
+    /// differs from the libx264 scale the generic value follows it.
+    pub fn describe(self, codec: VideoCodec) -> String {
+        let crf = |crf: u32| {
+            let own = codec.crf(crf);
+            if own == crf {
+                format!("CRF {}", crf)
+            } else {
+                format!("CRF {} ({} on the libx264 scale)", own, crf)
+            }
+        };
+        match self {
+            RateControl::Crf(quality) => format!("constant quality, {}", crf(quality)),
+            RateControl::CappedCrf {
+                crf: quality,
+                max_bitrate,
+            } => format!(
+                "constant quality, {} capped at {}kbps",
+                crf(quality),
+                max_bitrate
+            ),
+            RateControl::Abr(bitrate) => format!("average {}kbps", bitrate),
+            RateControl::Cbr(bitrate) => format!("constant {}kbps", bitrate),
+            RateControl::Qp(qp) => format!("constant quantizer, QP {}", qp),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset;
+    use clap::Parser;
+
+    /// Args from the command line `argv`, with `config` keys as if filled
+    /// from the config.
+    fn args(argv: &[&str], config: &[&str]) -> Args {
+        let argv = ["telegram-video-converter", "in.mp4"].iter().chain(argv);
+        let mut args = crate::Cli::try_parse_from(argv).unwrap().args;
+        args.config_keys = config.iter().map(|key| key.to_string()).collect();
+        args
+    }
+
+    fn sticker_mode(args: &Args) -> Result<RateMode, String> {
+        mode(args, &preset::builtin("sticker").unwrap())
+    }
+
+    fn h264_mode(args: &Args) -> Result<RateMode, String> {
+        mode(args, &preset::builtin("mobile-safe").unwrap())
+    }
+
+    #[test]
+    fn follows_the_options() {
+        assert_eq!(sticker_mode(&args(&[], &[])), Ok(RateMode::Crf));
+        assert_eq!(h264_mode(&args(&["--qp", "20"], &[])), Ok(RateMode::Qp));
+        assert_eq!(
+            sticker_mode(&args(&["--target-size", "200KB"], &[])),
+            Ok(RateMode::Abr)
+        );
+        assert_eq!(sticker_mode(&args(&["--two-pass"], &[])), Ok(RateMode::Abr));
+    }
+
+    #[test]
+    fn rejects_command_line_options_the_mode_ignores() {
+        let err = sticker_mode(&args(&["--bitrate", "400"], &[])).unwrap_err();
+        assert!(
+            err.starts_with("--bitrate has no effect with crf"),
+            "{}",
+            err
+        );
+        let argv = ["--rate-control", "crf", "--target-size", "200KB"];
+        assert_eq!(
+            sticker_mode(&args(&argv, &[])),
+            Err("--target-size has no effect with --rate-control crf".to_string())
+        );
+        let argv = ["--rate-control", "cbr", "--two-pass"];
+        assert!(sticker_mode(&args(&argv, &[])).is_err());
+    }
+
+    #[test]
+    fn ignores_config_options_the_mode_ignores() {
+        let argv = [
+            "--rate-control",
+            "crf",
+            "--target-size",
+            "200KB",
+            "--two-pass",
+        ];
+        let mut args = args(&argv, &["target_size", "two_pass"]);
+        assert_eq!(sticker_mode(&args), Ok(RateMode::Crf));
+        drop_unused(&mut args, RateMode::Crf);
+        assert_eq!(args.target_size, None);
+        assert!(!args.two_pass);
+    }
+
+    #[test]
+    fn command_line_options_pick_the_mode_over_the_config() {
+        let config_qp = args(&["--qp", "20", "--crf", "30"], &["qp"]);
+        assert_eq!(h264_mode(&config_qp), Ok(RateMode::CappedCrf));
+        let config_size = args(&["--target-size", "50MB", "--qp", "20"], &["target_size"]);
+        assert_eq!(h264_mode(&config_size), Ok(RateMode::Qp));
+    }
+}
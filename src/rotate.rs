@@ -0,0 +1,93 @@
+//! Rotation metadata handling and manual rotation or flipping.
+
+use crate::Args;
+use crate::probe::VideoStream;
+
+/// What to do with the rotation phones store next to the video.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum RotationPolicy {
+    /// Rotate the pixels and clear the metadata; plays upright everywhere
+    Bake,
+    /// Keep the pixels as recorded and carry the rotation as metadata
+    Metadata,
+}
+
+impl RotationPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            RotationPolicy::Bake => "bake",
+            RotationPolicy::Metadata => "metadata",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Flip {
+    /// Mirror left to right
+    Horizontal,
+    /// Mirror top to bottom
+    Vertical,
+}
+
+/// Parse the angle of `--rotate`, in degrees clockwise.
+pub fn parse_angle(text: &str) -> Result<u32, String> {
+    match text.trim().trim_end_matches('°') {
+        "90" => Ok(90),
+        "180" => Ok(180),
+        "270" | "-90" => Ok(270),
+        _ => Err(format!("'{}' is not 90, 180 or 270", text)),
+    }
+}
+
+pub fn policy(args: &Args) -> RotationPolicy {
+    args.rotation.unwrap_or(RotationPolicy::Bake)
+}
+
+/// Whether the pixels have to be re-encoded for the rotation and flips to
+/// take effect, with the reason why.
+pub fn needs_encode(video: &VideoStream, args: &Args) -> Option<String> {
+    if args.rotate.is_some() || !args.flip.is_empty() {
+        return Some("--rotate or --flip given".to_string());
+    }
+    if video.rotation != 0 && policy(args) == RotationPolicy::Bake {
+        return Some(format!(
+            "{}° rotation is only stored as metadata",
+            video.rotation
+        ));
+    }
+    None
+}
+
+/// Filters for `--rotate` and `--flip`, which apply on top of the rotation
+/// policy.
+pub fn filters(args: &Args) -> Vec<String> {
+    let mut filters: Vec<String> = match args.rotate {
+        Some(90) => vec!["transpose=clock".to_string()],
+        Some(180) => vec!["hflip".to_string(), "vflip".to_string()],
+        Some(270) => vec!["transpose=cclock".to_string()],
+        _ => Vec::new(),
+    };
+    for flip in &args.flip {
+        filters.push(
+            match flip {
+                Flip::Horizontal => "hflip",
+                Flip::Vertical => "vflip",
+            }
+            .to_string(),
+        );
+    }
+    filters
+}
+
+/// Width and height of the frames handed to the encoder.
+pub fn frame_size(video: &VideoStream, args: &Args) -> (u32, u32) {
+    let (width, height) = match policy(args) {
+        RotationPolicy::Bake => video.display_size(),
+        RotationPolicy::Metadata => (video.width, video.height),
+    };
+    if args.rotate.is_some_and(|angle| angle % 180 == 90) {
+        (height, width)
+    } else {
+        (width, height)
+    }
+}
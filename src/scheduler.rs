@@ -0,0 +1,153 @@
+//! Run batch conversions one after another or several at once.
+
+use crate::Converted;
+use std::fmt::Display;
+use std::io::{self, BufRead, BufReader};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+/// Context of one conversion: where its output goes and how many threads
+/// its ffmpeg may use.
+pub struct Job<'a> {
+    /// Prefix of every line printed, set when jobs run concurrently
+    prefix: Option<String>,
+    /// Encoder thread budget
+    pub threads: Option<usize>,
+    cancel: Option<&'a AtomicBool>,
+}
+
+impl Job<'static> {
+    /// A job that runs on its own, with nothing to share the terminal with.
+    pub fn standalone() -> Job<'static> {
+        Job {
+            prefix: None,
+            threads: None,
+            cancel: None,
+        }
+    }
+}
+
+impl Job<'_> {
+    /// Whether this job shares the terminal with others, so it can't draw
+    /// a progress bar in place.
+    pub fn is_concurrent(&self) -> bool {
+        self.prefix.is_some()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_some_and(|c| c.load(Ordering::Relaxed))
+    }
+
+    /// Print a line to stdout, tagged with the job when running concurrently.
+    pub fn say(&self, msg: impl Display) {
+        println!("{}{}", self.prefix.as_deref().unwrap_or(""), msg);
+    }
+
+    /// Print a line to stderr, tagged with the job when running concurrently.
+    pub fn warn(&self, msg: impl Display) {
+        eprintln!("{}{}", self.prefix.as_deref().unwrap_or(""), msg);
+    }
+
+    /// Run `cmd` showing its own output. Alongside other jobs, its stderr
+    /// is passed on a line at a time tagged with the job, and it is stopped
+    /// when the batch is cancelled.
+    pub fn run_shown(&self, cmd: &mut Command) -> io::Result<ExitStatus> {
+        if !self.is_concurrent() {
+            return cmd.status();
+        }
+
+        let mut child = cmd.stderr(Stdio::piped()).spawn()?;
+        let stderr = child.stderr.take().expect("stderr is piped");
+        // ffmpeg ends its progress lines with a carriage return
+        for chunk in BufReader::new(stderr).split(b'\r') {
+            let chunk = chunk?;
+            if self.is_cancelled() {
+                let _ = child.kill();
+                break;
+            }
+            for line in chunk.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+                self.warn(String::from_utf8_lossy(line));
+            }
+        }
+        child.wait()
+    }
+}
+
+/// Convert every input with up to `jobs` conversions at a time. Unless
+/// `fail_fast` is set a failure doesn't stop the other jobs; with it, the
+/// remaining inputs are skipped and running jobs are stopped.
+pub fn run<F>(
+    inputs: &[String],
+    jobs: usize,
+    fail_fast: bool,
+    convert: F,
+) -> Vec<(String, Result<Converted, String>)>
+where
+    F: Fn(&str, &Job) -> Result<Converted, String> + Sync,
+{
+    let jobs = jobs.clamp(1, inputs.len().max(1));
+    let concurrent = jobs > 1;
+
+    // Split the machine between the jobs instead of letting every ffmpeg
+    // spawn a thread per core
+    let threads = concurrent.then(|| {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        (cores / jobs).max(1)
+    });
+
+    let next = AtomicUsize::new(0);
+    let cancel = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<Result<Converted, String>>>> =
+        Mutex::new(inputs.iter().map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    let Some(input) = inputs.get(i) else {
+                        break;
+                    };
+
+                    let result = if cancel.load(Ordering::SeqCst) {
+                        Err("skipped after an earlier failure".to_string())
+                    } else {
+                        let job = Job {
+                            prefix: concurrent.then(|| format!("[{}/{}] ", i + 1, inputs.len())),
+                            threads,
+                            cancel: Some(&cancel),
+                        };
+                        if concurrent {
+                            job.say(format!("Starting {}", input));
+                        } else {
+                            println!("[{}/{}] {}", i + 1, inputs.len(), input);
+                        }
+
+                        let result = convert(input, &job);
+                        if let Err(e) = &result {
+                            job.warn(format!("✗ {}", e));
+                            if fail_fast {
+                                cancel.store(true, Ordering::SeqCst);
+                            }
+                        }
+                        if !concurrent {
+                            println!();
+                        }
+                        result
+                    };
+
+                    results.lock().unwrap()[i] = Some(result);
+                }
+            });
+        }
+    });
+
+    inputs
+        .iter()
+        .cloned()
+        .zip(results.into_inner().unwrap())
+        .map(|(input, result)| (input, result.expect("every input is visited")))
+        .collect()
+}
@@ -0,0 +1,192 @@
+//! Recognize screen recordings and tune the encode for them: sharp text,
+//! and no bits spent on frames that only repeat the previous one.
+
+use crate::Args;
+use crate::codec::Tune;
+use crate::preset::Preset;
+use crate::probe::MediaInfo;
+
+/// What the input shows, as given with `--content`.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Content {
+    /// Detect screen recordings from what ffprobe reports
+    Auto,
+    /// A screen recording: mostly still text with bursts of motion
+    Screen,
+    /// Anything else; encoded as usual
+    Other,
+}
+
+impl Content {
+    pub fn name(self) -> &'static str {
+        match self {
+            Content::Auto => "auto",
+            Content::Screen => "screen",
+            Content::Other => "other",
+        }
+    }
+}
+
+/// Screen recorders, as they name themselves in the file's tags; `screen`
+/// covers GNOME Screencast, SimpleScreenRecorder and the like.
+const RECORDERS: &[&str] = &[
+    "obs studio",
+    "obs-studio",
+    "kazam",
+    "sharex",
+    "bandicam",
+    "camtasia",
+    "screen",
+];
+
+/// Lossless codecs that screen recorders write and cameras don't.
+const SCREEN_CODECS: &[&str] = &["qtrle", "utvideo", "ffvhuff", "huffyuv", "png", "rawvideo"];
+
+/// Monitor resolutions that aren't also common camera sizes.
+const MONITOR_SIZES: &[(u32, u32)] = &[
+    (1280, 800),
+    (1366, 768),
+    (1440, 900),
+    (1536, 864),
+    (1600, 900),
+    (1680, 1050),
+    (1920, 1200),
+    (2560, 1080),
+    (2560, 1440),
+    (2560, 1600),
+    (2880, 1800),
+    (3440, 1440),
+];
+
+/// Bits per pixel and frame below which the video is mostly still; camera
+/// footage needs several times more.
+const STILL_BITS_PER_PIXEL: f64 = 0.05;
+
+/// How much lower the CRF is for screen recordings, which keeps small text
+/// sharp and costs little on frames that barely change.
+const SHARPER_CRF: u32 = 3;
+
+/// Share of the preset's bitrate, in percent, that screen recordings are
+/// held to. The lower CRF makes bursts of motion cost more, which would
+/// otherwise grow the files of presets that can't drop repeated frames.
+const SCREEN_BITRATE_PERCENT: u32 = 60;
+
+/// Why `media` looks like a screen recording, if it does.
+///
+/// A recorder named in the tags or a lossless screen codec settles it.
+/// Otherwise it takes two of: a monitor resolution, a variable frame rate
+/// and a very low bitrate for the frame size. Cameras record 1920x1080 at
+/// constant rates and high bitrates too, so an untagged recording like
+/// that goes unrecognized.
+pub fn detect(media: &MediaInfo) -> Option<String> {
+    let video = media.video()?;
+    // Phones store a rotation, screen recorders never do
+    if video.rotation != 0 {
+        return None;
+    }
+
+    if let Some(software) = media.software.iter().find(|software| {
+        let software = software.to_ascii_lowercase();
+        RECORDERS.iter().any(|recorder| software.contains(recorder))
+    }) {
+        return Some(format!("written by '{}'", software));
+    }
+    if SCREEN_CODECS.contains(&video.codec_name.as_str()) {
+        return Some(format!("{} is a screen recording codec", video.codec_name));
+    }
+
+    let mut hints = Vec::new();
+    if MONITOR_SIZES.contains(&(video.width, video.height)) {
+        hints.push(format!(
+            "{}x{} is a monitor resolution",
+            video.width, video.height
+        ));
+    }
+    if video.is_vfr() {
+        hints.push("the frame rate is variable".to_string());
+    }
+    let bit_rate = video.bit_rate.or(media.bit_rate);
+    if let (Some(bit_rate), Some(fps)) = (bit_rate, video.avg_frame_rate.or(video.frame_rate)) {
+        let pixels = f64::from(video.width) * f64::from(video.height) * fps;
+        if pixels > 0.0 && bit_rate as f64 / pixels < STILL_BITS_PER_PIXEL {
+            hints.push("the picture is mostly still".to_string());
+        }
+    }
+    (hints.len() >= 2).then(|| hints.join(", "))
+}
+
+/// Tune `preset` for screen content, leaving alone what was given on the
+/// command line or in the config. Repeated frames are only dropped when
+/// the preset's clients play variable frame rates.
+pub fn tune(preset: &mut Preset, args: &Args) {
+    preset.decimate = !preset.constant_frame_rate;
+    if args.tune.is_none() && preset.video_codec.supports_tune(Tune::Stillimage) {
+        preset.tune = Some(Tune::Stillimage);
+    }
+    if args.crf.is_none() {
+        preset.crf = preset.crf.saturating_sub(SHARPER_CRF);
+    }
+    if args.bitrate.is_none() {
+        preset.bitrate = preset.bitrate * SCREEN_BITRATE_PERCENT / 100;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::Json;
+    use crate::preset;
+    use clap::Parser;
+
+    /// ffprobe's report of a 1920x1080 H.264 stream at `rate` frames a
+    /// second on average, with the format's `tags`.
+    fn media(rate: &str, bit_rate: u64, tags: &str) -> MediaInfo {
+        let text = format!(
+            r#"{{"streams":[{{"codec_type":"video","codec_name":"h264",
+                "width":1920,"height":1080,"r_frame_rate":"60/1",
+                "avg_frame_rate":"{rate}","bit_rate":"{bit_rate}"}}],
+              "format":{{"format_name":"mov,mp4","tags":{{{tags}}}}}}}"#
+        );
+        MediaInfo::from_json(&Json::parse(&text).unwrap()).unwrap()
+    }
+
+    fn args(argv: &[&str]) -> Args {
+        let argv = ["telegram-video-converter", "in.mp4"].iter().chain(argv);
+        crate::Cli::try_parse_from(argv).unwrap().args
+    }
+
+    #[test]
+    fn recognizes_recorders_by_name() {
+        let tags = r#""comment":"Recorded with OBS Studio 30.1""#;
+        assert!(detect(&media("60/1", 8_000_000, tags)).is_some());
+    }
+
+    #[test]
+    fn needs_two_hints_without_a_recorder() {
+        // Variable, and mostly still at 0.005 bits per pixel
+        assert!(detect(&media("30/1", 300_000, "")).is_some());
+        assert!(detect(&media("60/1", 300_000, "")).is_none());
+        assert!(detect(&media("30/1", 8_000_000, "")).is_none());
+        // A constant 1080p60 recording looks like camera footage
+        assert!(detect(&media("60/1", 8_000_000, "")).is_none());
+    }
+
+    #[test]
+    fn tunes_for_text_within_a_lower_cap() {
+        let mut preset = preset::builtin("animation").unwrap();
+        tune(&mut preset, &args(&[]));
+        assert_eq!(preset.crf, 21);
+        assert_eq!(preset.bitrate, 900);
+        assert_eq!(preset.tune, Some(Tune::Stillimage));
+        assert!(!preset.decimate);
+    }
+
+    #[test]
+    fn keeps_given_quality_settings() {
+        let args = args(&["--preset", "desktop", "--crf", "18", "--bitrate", "3000"]);
+        let mut preset = preset::resolve(&args).unwrap();
+        tune(&mut preset, &args);
+        assert_eq!((preset.crf, preset.bitrate), (18, 3000));
+        assert!(preset.decimate);
+    }
+}
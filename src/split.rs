@@ -0,0 +1,165 @@
+//! Split a finished conversion into numbered parts that each fit a size or
+//! length limit.
+
+use crate::encode::run_ffmpeg;
+use crate::preset::Container;
+use crate::scheduler::Job;
+use crate::tempdir::TempDir;
+use crate::{Args, format_bytes};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Share of the size limit a part is planned for, as parts can only end
+/// at a keyframe and so come out a little longer than asked for.
+const SIZE_MARGIN: f64 = 0.9;
+
+/// How many times the parts are cut again when one is over the size limit.
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Upper bounds for a single part.
+pub struct Limits {
+    /// Bytes
+    pub size: Option<u64>,
+    /// Seconds
+    pub duration: Option<f64>,
+}
+
+/// `clip_telegram.mp4` → `clip_telegram_part3.mp4`
+pub fn part_path(output: &str, part: &str) -> String {
+    let path = Path::new(output);
+    let stem = path
+        .file_stem()
+        .map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    let name = match path.extension() {
+        Some(ext) => format!("{}_part{}.{}", stem, part, ext.to_string_lossy()),
+        None => format!("{}_part{}", stem, part),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+impl Limits {
+    /// Whether an output of `size` bytes and `duration` seconds has to be split.
+    pub fn exceeded_by(&self, size: u64, duration: f64) -> bool {
+        self.size.is_some_and(|max| size > max) || self.duration.is_some_and(|max| duration > max)
+    }
+}
+
+/// Cut `output` (`size` bytes, `duration` seconds) into parts at keyframes
+/// and remove it. Returns the parts with their sizes.
+pub fn split(
+    output: &str,
+    container: Container,
+    size: u64,
+    duration: f64,
+    limits: &Limits,
+    args: &Args,
+    job: &Job,
+) -> Result<Vec<(String, u64)>, String> {
+    let mut part_duration = limits.duration.unwrap_or(duration);
+    if let Some(max_size) = limits.size {
+        part_duration = part_duration.min(duration * max_size as f64 / size as f64 * SIZE_MARGIN);
+    }
+
+    // The segment muxer lists the parts it wrote, which tells them apart
+    // from leftovers of an earlier run
+    let list_dir =
+        TempDir::new("split").map_err(|e| format!("Failed to create split directory: {}", e))?;
+    let list_file = list_dir.path().join("parts");
+    let dir = Path::new(output).parent().unwrap_or(Path::new("."));
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        job.say(format!("Splitting into parts of {:.1}s", part_duration));
+        let cmd = segment_command(output, container, part_duration, &list_file, args);
+        run_ffmpeg(cmd, "Splitting", args.verbose, Some(duration), job)?;
+
+        let list = fs::read_to_string(&list_file)
+            .map_err(|e| format!("Cannot read the list of parts: {}", e))?;
+        let parts: Vec<(String, u64)> = list
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|name| {
+                let path = dir.join(name).to_string_lossy().into_owned();
+                let size = fs::metadata(&path).map_or(0, |m| m.len());
+                (path, size)
+            })
+            .collect();
+
+        let largest = parts.iter().map(|(_, size)| *size).max().unwrap_or(0);
+        match limits.size {
+            Some(max_size) if largest > max_size && attempt < MAX_ATTEMPTS => {
+                job.say(format!(
+                    "A part is {}, over the {} limit; cutting shorter parts",
+                    format_bytes(largest),
+                    format_bytes(max_size)
+                ));
+                for (path, _) in &parts {
+                    let _ = fs::remove_file(path);
+                }
+                part_duration *= max_size as f64 / largest as f64 * SIZE_MARGIN;
+            }
+            Some(max_size) if largest > max_size => {
+                job.warn(format!(
+                    "  Warning: a part is {}, over the {} limit; the input has too few keyframes",
+                    format_bytes(largest),
+                    format_bytes(max_size)
+                ));
+                return finish(output, parts);
+            }
+            _ => return finish(output, parts),
+        }
+    }
+    unreachable!("the last attempt always returns")
+}
+
+/// Drop the unsplit output once its parts are written.
+fn finish(output: &str, parts: Vec<(String, u64)>) -> Result<Vec<(String, u64)>, String> {
+    fs::remove_file(output).map_err(|e| format!("Cannot remove '{}': {}", output, e))?;
+    Ok(parts)
+}
+
+fn segment_command(
+    output: &str,
+    container: Container,
+    part_duration: f64,
+    list_file: &Path,
+    args: &Args,
+) -> Command {
+    let mut cmd = Command::new("ffmpeg");
+    if !args.verbose {
+        cmd.args(["-progress", "pipe:1", "-nostats"]);
+    }
+    cmd.args(["-i", output, "-map", "0", "-c", "copy"]);
+
+    // Every part starts at a keyframe with its own timestamps, so each one
+    // plays on its own
+    cmd.args([
+        "-f",
+        "segment",
+        "-segment_time",
+        &format!("{:.3}", part_duration),
+        "-segment_format",
+        container.format(),
+        "-reset_timestamps",
+        "1",
+        "-segment_start_number",
+        "1",
+        "-segment_list_type",
+        "flat",
+        "-segment_list",
+    ]);
+    cmd.arg(list_file);
+    if container == Container::Mp4 {
+        cmd.args(["-segment_format_options", "movflags=+faststart"]);
+    }
+    if args.overwrite {
+        cmd.arg("-y");
+    }
+
+    // The segment muxer numbers parts through a printf pattern
+    cmd.arg(part_path(&output.replace('%', "%%"), "%d"));
+    if !args.verbose {
+        cmd.args(["-loglevel", "error"]);
+    }
+    cmd
+}
@@ -0,0 +1,274 @@
+//! Upload finished videos with the Telegram Bot API.
+//!
+//! The request goes through curl, which brings the TLS support
+//! api.telegram.org needs.
+
+use crate::Args;
+use crate::json::Json;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+pub const DEFAULT_API_URL: &str = "https://api.telegram.org";
+
+/// Where uploads go, checked before any conversion starts.
+pub struct Bot<'a> {
+    token: &'a str,
+    chat_id: &'a str,
+    api_url: &'a str,
+    caption: Option<&'a str>,
+}
+
+/// One video to upload, with what Telegram shows before it downloads it.
+pub struct Video<'a> {
+    pub path: &'a str,
+    pub width: u32,
+    pub height: u32,
+    pub duration: Option<f64>,
+    pub thumbnail: Option<&'a str>,
+    /// Added to the caption as `(part/count)` when a video was split
+    pub part: Option<(usize, usize)>,
+}
+
+impl<'a> Bot<'a> {
+    pub fn from_args(args: &'a Args) -> Result<Bot<'a>, String> {
+        let token = args
+            .bot_token
+            .as_deref()
+            .ok_or("--send needs bot_token in the config file")?;
+        let chat_id = args
+            .chat_id
+            .as_deref()
+            .ok_or("--send needs --chat-id or chat_id in the config file")?;
+        if !is_curl_available() {
+            return Err("--send needs curl, which is not installed or not in PATH".to_string());
+        }
+        Ok(Bot {
+            token,
+            chat_id,
+            api_url: args.api_url.as_deref().unwrap_or(DEFAULT_API_URL),
+            caption: args.caption.as_deref(),
+        })
+    }
+
+    pub fn chat_id(&self) -> &str {
+        self.chat_id
+    }
+
+    /// Upload `video` with `sendVideo`.
+    pub fn send_video(&self, video: &Video) -> Result<(), String> {
+        let mut cmd = Command::new("curl");
+        cmd.args(["--silent", "--show-error", "--config", "-"]);
+
+        // Text fields go in with --form-string so a leading @ or < in a
+        // caption is never read as a file name
+        let mut text = |name: &str, value: &str| {
+            cmd.arg("--form-string").arg(format!("{}={}", name, value));
+        };
+        text("chat_id", self.chat_id);
+        text("supports_streaming", "true");
+        text("width", &video.width.to_string());
+        text("height", &video.height.to_string());
+        if let Some(duration) = video.duration {
+            text("duration", &(duration.round() as u64).to_string());
+        }
+        let caption = match (self.caption, video.part) {
+            (Some(caption), Some((part, count))) => {
+                Some(format!("{} ({}/{})", caption, part, count))
+            }
+            (Some(caption), None) => Some(caption.to_string()),
+            (None, Some((part, count))) => Some(format!("{}/{}", part, count)),
+            (None, None) => None,
+        };
+        if let Some(caption) = &caption {
+            text("caption", caption);
+        }
+
+        cmd.arg("--form")
+            .arg(format!("video=@{}", quote(video.path)));
+        if let Some(thumbnail) = video.thumbnail {
+            cmd.arg("--form")
+                .arg(format!("thumbnail=@{}", quote(thumbnail)));
+        }
+
+        // The token is part of the URL, so it is handed over on stdin
+        // rather than showing up in the process list
+        let url = format!(
+            "{}/bot{}/sendVideo",
+            self.api_url.trim_end_matches('/'),
+            self.token
+        );
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to execute curl: {}", e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            writeln!(stdin, "url = {}", quote(&url))
+                .map_err(|e| format!("Failed to pass the URL to curl: {}", e))?;
+        }
+        let output = child
+            .wait_with_output()
+            .map_err(|e| format!("Failed to execute curl: {}", e))?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "Upload failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        let body = String::from_utf8_lossy(&output.stdout);
+        let response = Json::parse(&body)
+            .map_err(|_| format!("Unexpected response from the Bot API: {}", body.trim()))?;
+        match response.get("ok") {
+            Some(Json::Bool(true)) => Ok(()),
+            _ => Err(format!(
+                "Telegram refused the video: {}",
+                response
+                    .get("description")
+                    .and_then(Json::as_str)
+                    .unwrap_or("no reason given")
+            )),
+        }
+    }
+}
+
+/// Double quote `value` for curl, which keeps commas and semicolons in
+/// file names from being read as `-F` options.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn is_curl_available() -> bool {
+    Command::new("curl").arg("--version").output().is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serve one request on a loopback port with `response` as the JSON
+    /// body, and hand back the request as it arrived.
+    fn mock_api(response: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            let header_end = loop {
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 0, "connection closed before the headers ended");
+                request.extend_from_slice(&buf[..n]);
+                if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end + 4;
+                }
+            };
+            let headers = String::from_utf8_lossy(&request[..header_end]).to_lowercase();
+            let length: usize = headers
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map(|n| n.trim().parse().unwrap())
+                .expect("multipart uploads have a length");
+            if headers.contains("expect: 100-continue") {
+                stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+            }
+            while request.len() < header_end + length {
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 0, "connection closed before the body ended");
+                request.extend_from_slice(&buf[..n]);
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.len(),
+                response
+            )
+            .unwrap();
+            String::from_utf8_lossy(&request).into_owned()
+        });
+        (url, server)
+    }
+
+    /// The value of text field `name` in a multipart `request`.
+    fn field<'r>(request: &'r str, name: &str) -> Option<&'r str> {
+        let start = format!("name=\"{}\"\r\n\r\n", name);
+        let value = &request[request.find(&start)? + start.len()..];
+        value.split("\r\n").next()
+    }
+
+    fn video(dir: &TempDir) -> String {
+        let path = dir.path().join("clip.mp4");
+        std::fs::write(&path, b"not really a video").unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn sends_the_form_fields_to_the_token_url() {
+        let (api_url, server) = mock_api(r#"{"ok":true,"result":{}}"#);
+        let dir = TempDir::new("test").unwrap();
+        let path = video(&dir);
+        let bot = Bot {
+            token: "123:secret",
+            chat_id: "@channel",
+            api_url: &api_url,
+            caption: Some("@holiday; day 1"),
+        };
+        bot.send_video(&Video {
+            path: &path,
+            width: 1280,
+            height: 720,
+            duration: Some(12.6),
+            thumbnail: None,
+            part: Some((2, 3)),
+        })
+        .unwrap();
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /bot123:secret/sendVideo HTTP/1.1\r\n"));
+        assert_eq!(field(&request, "chat_id"), Some("@channel"));
+        assert_eq!(field(&request, "supports_streaming"), Some("true"));
+        assert_eq!(field(&request, "width"), Some("1280"));
+        assert_eq!(field(&request, "height"), Some("720"));
+        assert_eq!(field(&request, "duration"), Some("13"));
+        assert_eq!(field(&request, "caption"), Some("@holiday; day 1 (2/3)"));
+        assert!(request.contains("name=\"video\"; filename=\"clip.mp4\""));
+        assert!(request.contains("not really a video"));
+        assert!(!request.contains("name=\"thumbnail\""));
+    }
+
+    #[test]
+    fn reports_why_telegram_refused_the_video() {
+        let (api_url, server) = mock_api(
+            r#"{"ok":false,"error_code":400,"description":"Bad Request: chat not found"}"#,
+        );
+        let dir = TempDir::new("test").unwrap();
+        let path = video(&dir);
+        let bot = Bot {
+            token: "123:secret",
+            chat_id: "42",
+            api_url: &api_url,
+            caption: None,
+        };
+        let result = bot.send_video(&Video {
+            path: &path,
+            width: 512,
+            height: 512,
+            duration: None,
+            thumbnail: None,
+            part: None,
+        });
+
+        server.join().unwrap();
+        assert_eq!(
+            result,
+            Err("Telegram refused the video: Bad Request: chat not found".to_string())
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! JPEG thumbnails the way Telegram accepts them for video uploads, and
+//! embedding them as MP4 cover art.
+
+use crate::Args;
+use crate::scheduler::Job;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Longest side of a thumbnail in pixels.
+const MAX_SIDE: u32 = 320;
+
+/// Largest thumbnail in bytes.
+const MAX_SIZE: u64 = 200 * 1024;
+
+/// JPEG qualities to try in order, on ffmpeg's scale where 2 is the best
+/// and 31 the worst.
+const QUALITIES: &[u32] = &[2, 5, 10, 20, 31];
+
+/// Average brightness (0-255) a frame must exceed to be picked
+/// automatically, which keeps fades and black intros out.
+const MIN_BRIGHTNESS: u32 = 32;
+
+/// How many frames the automatic pick compares.
+const PICK_FRAMES: u32 = 100;
+
+/// `clip_telegram.mp4` → `clip_telegram.jpg`
+pub fn thumbnail_path(output: &str) -> String {
+    Path::new(output)
+        .with_extension("jpg")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Write a thumbnail of `video` to `path`, from the frame at `at` seconds
+/// or, without it, from a representative frame that isn't black.
+pub fn create(
+    video: &str,
+    path: &str,
+    at: Option<f64>,
+    duration: Option<f64>,
+    args: &Args,
+    job: &Job,
+) -> Result<u64, String> {
+    for &quality in QUALITIES {
+        let _ = fs::remove_file(path);
+        match at {
+            Some(at) => run(extract(video, path, at, None, quality), args, job)?,
+            None => {
+                // Skip the intro, which is often a logo or a fade in
+                let start = duration.map_or(0.0, |d| d * 0.1);
+                run(
+                    extract(video, path, start, Some(MIN_BRIGHTNESS), quality),
+                    args,
+                    job,
+                )?;
+                // A video that is dark all the way through has no frame
+                // bright enough, so take any representative one
+                if !Path::new(path).exists() {
+                    run(extract(video, path, start, None, quality), args, job)?;
+                }
+            }
+        }
+
+        let size = fs::metadata(path)
+            .map_err(|_| format!("ffmpeg wrote no thumbnail for '{}'", video))?
+            .len();
+        if size <= MAX_SIZE {
+            return Ok(size);
+        }
+    }
+    Err(format!(
+        "the thumbnail of '{}' is over {}KB even at the lowest quality",
+        video,
+        MAX_SIZE / 1024
+    ))
+}
+
+fn extract(video: &str, path: &str, at: f64, min_brightness: Option<u32>, quality: u32) -> Command {
+    let mut filters = Vec::new();
+    if let Some(min_brightness) = min_brightness {
+        filters.push("signalstats".to_string());
+        filters.push(format!(
+            "metadata=mode=select:key=lavfi.signalstats.YAVG:value={}:function=greater",
+            min_brightness
+        ));
+        filters.push(format!("thumbnail={}", PICK_FRAMES));
+    }
+    filters.push(format!(
+        "scale=w='min({0},iw)':h='min({0},ih)':force_original_aspect_ratio=decrease",
+        MAX_SIDE
+    ));
+
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args(["-ss", &at.to_string(), "-i", video, "-map", "0:v:0"]);
+    cmd.args(["-vf", &filters.join(",")]);
+    // Full range 4:2:0 is the JPEG flavour every client decodes
+    cmd.args(["-pix_fmt", "yuvj420p", "-q:v", &quality.to_string()]);
+    cmd.args(["-frames:v", "1", "-update", "1", "-y", path]);
+    cmd
+}
+
+/// Embed `thumbnail` into the MP4 `video` as its cover art.
+pub fn embed(video: &str, thumbnail: &str, args: &Args, job: &Job) -> Result<(), String> {
+    let temp = Path::new(video)
+        .with_extension("cover.mp4")
+        .to_string_lossy()
+        .into_owned();
+
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args(["-i", video, "-i", thumbnail]);
+    cmd.args(["-map", "0", "-map", "1", "-c", "copy"]);
+    cmd.args(["-disposition:v:1", "attached_pic"]);
+    cmd.args(["-movflags", "+faststart", "-f", "mp4", "-y", &temp]);
+
+    let result = run(cmd, args, job).and_then(|()| {
+        fs::rename(&temp, video).map_err(|e| format!("Cannot replace '{}': {}", video, e))
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Run a quick ffmpeg step, quietly unless in verbose mode.
+fn run(mut cmd: Command, args: &Args, job: &Job) -> Result<(), String> {
+    if args.verbose {
+        let status = job
+            .run_shown(&mut cmd)
+            .map_err(|e| format!("Failed to execute ffmpeg: {}", e))?;
+        return if status.success() {
+            Ok(())
+        } else {
+            Err(format!("ffmpeg failed with exit code: {:?}", status.code()))
+        };
+    }
+
+    let output = cmd
+        .args(["-loglevel", "error"])
+        .output()
+        .map_err(|e| format!("Failed to execute ffmpeg: {}", e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "ffmpeg failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
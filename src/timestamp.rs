@@ -0,0 +1,155 @@
+//! Points in time and lengths given on the command line.
+
+use std::fmt;
+
+/// A time in the input, written as `1:23.5`, `83.5s`, `10m` or a frame
+/// number such as `2000f`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timestamp {
+    Seconds(f64),
+    /// Frame number, turned into seconds with the input's frame rate
+    Frame(u64),
+}
+
+/// Parse a timestamp: `[[h:]m:]s` clock times, a number with an `ms`, `s`,
+/// `m` or `h` unit (plain numbers are seconds), or a frame number ending in `f`.
+pub fn parse(text: &str) -> Result<Timestamp, String> {
+    let text = text.trim();
+    let invalid = || {
+        format!(
+            "invalid time '{}' (expected e.g. 1:23.5, 83.5s, 10m or 2000f)",
+            text
+        )
+    };
+
+    if let Some(frame) = text.strip_suffix('f') {
+        return frame
+            .trim()
+            .parse()
+            .map(Timestamp::Frame)
+            .map_err(|_| invalid());
+    }
+
+    let seconds = if text.contains(':') {
+        // Each field is a number of the next larger unit: h:m:s or m:s
+        let fields: Vec<&str> = text.split(':').collect();
+        if fields.len() > 3 {
+            return Err(invalid());
+        }
+        let (last, rest) = fields.split_last().expect("split yields a field");
+        // Only digits and a decimal point, so no signs or exponents
+        if !last.bytes().all(|b| b.is_ascii_digit() || b == b'.') {
+            return Err(invalid());
+        }
+        let mut seconds: f64 = last.parse().map_err(|_| invalid())?;
+        if seconds >= 60.0 {
+            return Err(invalid());
+        }
+        for (i, field) in rest.iter().rev().enumerate() {
+            let value: u64 = field.parse().map_err(|_| invalid())?;
+            // Minutes below hours are 0-59 as well; the first field is open
+            if i + 1 < rest.len() && value >= 60 {
+                return Err(invalid());
+            }
+            seconds += value as f64 * 60f64.powi(i as i32 + 1);
+        }
+        seconds
+    } else {
+        let split = text
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(text.len());
+        let (number, unit) = text.split_at(split);
+        let number: f64 = number.parse().map_err(|_| invalid())?;
+        let scale = match unit.trim() {
+            "" | "s" => 1.0,
+            "ms" => 0.001,
+            "m" | "min" => 60.0,
+            "h" => 3600.0,
+            _ => return Err(invalid()),
+        };
+        number * scale
+    };
+
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(invalid());
+    }
+    Ok(Timestamp::Seconds(seconds))
+}
+
+impl Timestamp {
+    /// Seconds from the start of the input, given its frame rate.
+    pub fn seconds(self, fps: Option<f64>) -> Result<f64, String> {
+        match self {
+            Timestamp::Seconds(seconds) => Ok(seconds),
+            Timestamp::Frame(frame) => fps
+                .map(|fps| frame as f64 / fps)
+                .ok_or_else(|| format!("frame {} given, but the frame rate is unknown", frame)),
+        }
+    }
+}
+
+/// Written back in a form `parse` reads.
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timestamp::Seconds(seconds) => write!(f, "{}s", seconds),
+            Timestamp::Frame(frame) => write!(f, "{}f", frame),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(text: &str) -> f64 {
+        match parse(text) {
+            Ok(Timestamp::Seconds(seconds)) => seconds,
+            other => panic!("'{}' parsed as {:?}", text, other),
+        }
+    }
+
+    #[test]
+    fn parses_clock_times() {
+        assert_eq!(seconds("1:23.5"), 83.5);
+        assert_eq!(seconds("0:05"), 5.0);
+        assert_eq!(seconds("1:02:03"), 3723.0);
+        // The leading field isn't limited to 59
+        assert_eq!(seconds("90:00"), 5400.0);
+    }
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(seconds("83.5s"), 83.5);
+        assert_eq!(seconds("83.5"), 83.5);
+        assert_eq!(seconds("500ms"), 0.5);
+        assert_eq!(seconds("10m"), 600.0);
+        assert_eq!(seconds("10 min"), 600.0);
+        assert_eq!(seconds("2h"), 7200.0);
+        assert_eq!(parse("2000f"), Ok(Timestamp::Frame(2000)));
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        for text in [
+            "", "abc", "-5", "-5s", "10x", "1:-5", "1:+5", "1:75", "1:60", "1:60:00", "1:5e1",
+            "1:2:3:4", "1::3", ":30", "1.5:00", "-1f", "1.5f", "f",
+        ] {
+            assert!(parse(text).is_err(), "'{}' was accepted", text);
+        }
+    }
+
+    #[test]
+    fn converts_frames_with_the_frame_rate() {
+        assert_eq!(Timestamp::Frame(60).seconds(Some(30.0)), Ok(2.0));
+        assert!(Timestamp::Frame(60).seconds(None).is_err());
+        assert_eq!(Timestamp::Seconds(1.5).seconds(None), Ok(1.5));
+    }
+
+    #[test]
+    fn writes_times_back_in_a_parseable_form() {
+        for time in [Timestamp::Seconds(83.5), Timestamp::Frame(2000)] {
+            assert_eq!(parse(&time.to_string()), Ok(time));
+        }
+    }
+}
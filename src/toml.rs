@@ -0,0 +1,413 @@
+//! Minimal TOML reader covering what the config file needs: tables, dotted
+//! table headers, strings, numbers, booleans and arrays.
+
+use std::collections::BTreeMap;
+
+pub type Table = BTreeMap<String, Value>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Table(_) => "table",
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Table, String> {
+    let mut parser = Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+        line: 1,
+    };
+    parser.document()
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> String {
+        error_at(self.line, msg)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_spaces(&mut self) {
+        while let Some(' ' | '\t') = self.peek() {
+            self.bump();
+        }
+    }
+
+    /// Skip whitespace, newlines and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r' | '\n') => {
+                    self.bump();
+                }
+                Some('#') => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.bump();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Expect the end of a line, allowing a trailing comment.
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_spaces();
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+        if self.peek() == Some('\r') {
+            self.bump();
+        }
+        match self.bump() {
+            None | Some('\n') => Ok(()),
+            Some(c) => Err(self.error(&format!("unexpected '{}'", c))),
+        }
+    }
+
+    fn document(&mut self) -> Result<Table, String> {
+        let mut root = Table::new();
+        let mut current: Vec<String> = Vec::new();
+        let mut headers: Vec<Vec<String>> = Vec::new();
+
+        loop {
+            self.skip_blank();
+            // Errors found after the line is read still point at it
+            let line = self.line;
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    self.bump();
+                    current = self.key()?;
+                    self.skip_spaces();
+                    if self.bump() != Some(']') {
+                        return Err(self.error("expected ']' after table name"));
+                    }
+                    self.end_of_line()?;
+                    if headers.contains(&current) {
+                        return Err(error_at(
+                            line,
+                            &format!("duplicate table '[{}]'", current.join(".")),
+                        ));
+                    }
+                    headers.push(current.clone());
+                    table_at(&mut root, &current).map_err(|e| error_at(line, &e))?;
+                }
+                Some(_) => {
+                    let key = self.key()?;
+                    self.skip_spaces();
+                    if self.bump() != Some('=') {
+                        return Err(self.error("expected '=' after key"));
+                    }
+                    self.skip_spaces();
+                    let value = self.value()?;
+                    self.end_of_line()?;
+
+                    let (last, parents) = key.split_last().expect("keys are never empty");
+                    let path: Vec<String> = current.iter().chain(parents).cloned().collect();
+                    let table = table_at(&mut root, &path).map_err(|e| error_at(line, &e))?;
+                    if table.insert(last.clone(), value).is_some() {
+                        return Err(error_at(line, &format!("duplicate key '{}'", last)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// A possibly dotted key such as `profiles.lowres`.
+    fn key(&mut self) -> Result<Vec<String>, String> {
+        let mut parts = Vec::new();
+        loop {
+            self.skip_spaces();
+            let part = match self.peek() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let mut part = String::new();
+                    while let Some(c) = self.peek()
+                        && (c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                    {
+                        part.push(c);
+                        self.bump();
+                    }
+                    if part.is_empty() {
+                        return Err(self.error("expected a key"));
+                    }
+                    part
+                }
+            };
+            parts.push(part);
+            self.skip_spaces();
+            if self.peek() != Some('.') {
+                return Ok(parts);
+            }
+            self.bump();
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some('t' | 'f') => {
+                let word = self.word();
+                match word.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    _ => Err(self.error(&format!("invalid value '{}'", word))),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' => {
+                let word = self.word().replace('_', "");
+                if let Ok(i) = word.parse::<i64>() {
+                    Ok(Value::Integer(i))
+                } else {
+                    word.parse::<f64>()
+                        .map(Value::Float)
+                        .map_err(|_| self.error(&format!("invalid number '{}'", word)))
+                }
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(c) = self.peek()
+            && (c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+' | '.'))
+        {
+            word.push(c);
+            self.bump();
+        }
+        word
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.bump();
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_blank();
+            match self.bump() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return Err(self.error("expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.bump();
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some('"') => return Ok(out),
+                Some('\\') => match self.bump() {
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some('u') => {
+                        let hex: String = (0..4).filter_map(|_| self.bump()).collect();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| self.error("invalid unicode escape"))?;
+                        out.push(c);
+                    }
+                    _ => return Err(self.error("invalid escape")),
+                },
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        self.bump();
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some('\'') => return Ok(out),
+                Some(c) => out.push(c),
+            }
+        }
+    }
+}
+
+fn error_at(line: usize, msg: &str) -> String {
+    format!("line {}: {}", line, msg)
+}
+
+/// The table at `path`, creating missing tables on the way.
+fn table_at<'a>(root: &'a mut Table, path: &[String]) -> Result<&'a mut Table, String> {
+    let mut table = root;
+    for part in path {
+        let entry = table
+            .entry(part.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+        table = match entry {
+            Value::Table(t) => t,
+            _ => return Err(format!("'{}' is not a table", part)),
+        };
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(value: &Value) -> &Table {
+        match value {
+            Value::Table(table) => table,
+            other => panic!("expected a table, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_values() {
+        let config = parse(
+            "preset = \"desktop\" # trailing comment\n\
+             crf = 20\n\
+             speed_factor = -1.5\n\
+             target_size = 45_000_000\n\
+             send = true\n\
+             caption = 'C:\\clips'\r\n",
+        )
+        .unwrap();
+        assert_eq!(config["preset"], Value::String("desktop".to_string()));
+        assert_eq!(config["crf"], Value::Integer(20));
+        assert_eq!(config["speed_factor"], Value::Float(-1.5));
+        assert_eq!(config["target_size"], Value::Integer(45_000_000));
+        assert_eq!(config["send"], Value::Boolean(true));
+        assert_eq!(config["caption"], Value::String("C:\\clips".to_string()));
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        let config = parse(r#"caption = "say \"hi\"\t\\ \u00e9""#).unwrap();
+        assert_eq!(
+            config["caption"],
+            Value::String("say \"hi\"\t\\ é".to_string())
+        );
+        assert!(parse(r#"caption = "\q""#).is_err());
+        assert!(parse(r#"caption = "\ud800""#).is_err());
+        assert!(parse("caption = \"open\ncaption = 1").is_err());
+    }
+
+    #[test]
+    fn nests_dotted_headers_and_keys() {
+        let config = parse(
+            "crf = 23\n\
+             [profiles.lowres]\n\
+             max_height = 480\n\
+             [profiles.\"share web\"]\n\
+             audio.bitrate = 96\n",
+        )
+        .unwrap();
+        let profiles = table(&config["profiles"]);
+        assert_eq!(
+            table(&profiles["lowres"])["max_height"],
+            Value::Integer(480)
+        );
+        let web = table(&profiles["share web"]);
+        assert_eq!(table(&web["audio"])["bitrate"], Value::Integer(96));
+        assert_eq!(config["crf"], Value::Integer(23));
+    }
+
+    #[test]
+    fn reads_multi_line_arrays() {
+        let config = parse(
+            "include = [\n\
+             \x20   \"*.mp4\", # phones\n\
+             \n\
+             \x20   '*.mkv',\n\
+             ]\n\
+             empty = []\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config["include"],
+            Value::Array(vec![
+                Value::String("*.mp4".to_string()),
+                Value::String("*.mkv".to_string()),
+            ])
+        );
+        assert_eq!(config["empty"], Value::Array(Vec::new()));
+        assert!(parse("include = [\"a\" \"b\"]").is_err());
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        assert_eq!(
+            parse("crf = 20\n\ncrf = 22\n"),
+            Err("line 3: duplicate key 'crf'".to_string())
+        );
+        assert_eq!(
+            parse("[profiles.a]\ncrf = 1\n[profiles.a]\n"),
+            Err("line 3: duplicate table '[profiles.a]'".to_string())
+        );
+        assert_eq!(
+            parse("profiles = 1\n[profiles.a]\n"),
+            Err("line 2: 'profiles' is not a table".to_string())
+        );
+        assert!(parse("[a]\nb.c = 1\n[a.b]\nc = 2\n").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(
+            parse("crf 20"),
+            Err("line 1: expected '=' after key".to_string())
+        );
+        assert!(parse("crf = 20 21").is_err());
+        assert!(parse("crf =").is_err());
+        assert!(parse("send = yes").is_err());
+        assert!(parse("[profiles").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+//! Watch a directory with inotify and convert recordings once they are done.
+
+use crate::batch::Filter;
+use crate::preset::Container;
+use crate::split::part_path;
+use crate::{Converted, generate_output_path};
+use std::collections::HashMap;
+use std::ffi::{CString, c_char, c_int};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::fd::FromRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+const IN_CLOSE_WRITE: u32 = 0x0000_0008;
+const IN_MOVED_TO: u32 = 0x0000_0080;
+const IN_CLOEXEC: c_int = 0o2_000_000;
+
+/// Size of `struct inotify_event` without the trailing name.
+const EVENT_HEADER: usize = 16;
+
+unsafe extern "C" {
+    fn inotify_init1(flags: c_int) -> c_int;
+    fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: u32) -> c_int;
+}
+
+#[derive(clap::Args)]
+pub struct WatchOptions {
+    /// Seconds a file's size must stay unchanged before it is converted
+    #[arg(long, default_value = "5")]
+    settle: u64,
+
+    /// Mark files already in the directory as done instead of converting them
+    #[arg(long)]
+    skip_existing: bool,
+
+    /// File that remembers converted recordings across restarts
+    /// (defaults to $XDG_STATE_HOME/telegram-video-converter/watch-state)
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+}
+
+/// Recordings that were already converted, keyed by path with their size
+/// at the time, so a file that is rewritten later is picked up again.
+struct State {
+    path: PathBuf,
+    done: HashMap<PathBuf, u64>,
+}
+
+impl State {
+    fn load(path: PathBuf) -> io::Result<State> {
+        let mut done = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                for line in text.lines() {
+                    if let Some((size, file)) = line.split_once('\t')
+                        && let Ok(size) = size.parse()
+                    {
+                        done.insert(PathBuf::from(file), size);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(State { path, done })
+    }
+
+    fn is_done(&self, file: &Path) -> bool {
+        let size = fs::metadata(file).map_or(0, |m| m.len());
+        self.done.get(file) == Some(&size)
+    }
+
+    fn mark_done(&mut self, file: &Path) -> io::Result<()> {
+        let size = fs::metadata(file).map_or(0, |m| m.len());
+        self.done.insert(file.to_path_buf(), size);
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut out = File::options().create(true).append(true).open(&self.path)?;
+        writeln!(out, "{}\t{}", size, file.display())
+    }
+}
+
+fn default_state_file() -> PathBuf {
+    let base = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .unwrap_or_else(|| {
+            let home = std::env::var_os("HOME").unwrap_or_default();
+            PathBuf::from(home).join(".local/state")
+        });
+    base.join("telegram-video-converter").join("watch-state")
+}
+
+/// Watch `dir` until interrupted, converting every finished recording
+/// that passes `filter` into a `container` file with `convert`.
+pub fn run<F>(
+    dir: &str,
+    options: &WatchOptions,
+    filter: &Filter,
+    container: Container,
+    convert: F,
+) -> Result<(), String>
+where
+    F: Fn(&str) -> Result<Converted, String>,
+{
+    let dir = fs::canonicalize(dir).map_err(|e| format!("Cannot watch '{}': {}", dir, e))?;
+    let state_file = options
+        .state_file
+        .clone()
+        .unwrap_or_else(default_state_file);
+    let mut state = State::load(state_file.clone())
+        .map_err(|e| format!("Cannot read state file '{}': {}", state_file.display(), e))?;
+
+    // Start listening before the initial scan so nothing slips in between
+    let mut events =
+        watch_events(&dir).map_err(|e| format!("Cannot watch '{}': {}", dir.display(), e))?;
+
+    let mut existing: Vec<PathBuf> = fs::read_dir(&dir)
+        .map_err(|e| format!("Cannot read '{}': {}", dir.display(), e))?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.is_file() && filter.matches(path))
+        .collect();
+    existing.sort();
+
+    let process = |path: &Path, state: &mut State, initial_scan: bool| {
+        if state.is_done(path) {
+            return;
+        }
+        if initial_scan && options.skip_existing {
+            if let Err(e) = state.mark_done(path) {
+                eprintln!("Warning: cannot update state file: {}", e);
+            }
+            return;
+        }
+
+        println!("New recording: {}", path.display());
+        wait_until_finished(path, Duration::from_secs(options.settle));
+
+        match convert(&path.to_string_lossy()) {
+            Ok(_) => {
+                if let Err(e) = state.mark_done(path) {
+                    eprintln!("Warning: cannot update state file: {}", e);
+                }
+            }
+            Err(e) => eprintln!("✗ {}", e),
+        }
+        println!();
+    };
+
+    for path in &existing {
+        // Files that already have a converted copy count as done
+        let output = generate_output_path(&path.to_string_lossy(), container);
+        if !Path::new(&output).exists() && !Path::new(&part_path(&output, "1")).exists() {
+            process(path, &mut state, true);
+        }
+    }
+
+    println!("Watching '{}' for new recordings...", dir.display());
+    loop {
+        let names = events
+            .next_batch()
+            .map_err(|e| format!("Failed to read inotify events: {}", e))?;
+        for name in names {
+            let path = dir.join(name);
+            if path.is_file() && filter.matches(&path) {
+                process(&path, &mut state, false);
+            }
+        }
+    }
+}
+
+/// File names reported by inotify for one watched directory.
+struct Events {
+    file: File,
+    buf: Vec<u8>,
+}
+
+fn watch_events(dir: &Path) -> io::Result<Events> {
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    // SAFETY: plain syscalls; the descriptor is owned by the returned File
+    let fd = unsafe { inotify_init1(IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+
+    // A finished recording is either closed after writing or moved in
+    let wd = unsafe { inotify_add_watch(fd, path.as_ptr(), IN_CLOSE_WRITE | IN_MOVED_TO) };
+    if wd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(Events {
+        file,
+        buf: vec![0; 64 * 1024],
+    })
+}
+
+impl Events {
+    /// Block until at least one event arrives and return the names it carries.
+    fn next_batch(&mut self) -> io::Result<Vec<PathBuf>> {
+        let len = self.file.read(&mut self.buf)?;
+        let mut names = Vec::new();
+        let mut offset = 0;
+
+        while offset + EVENT_HEADER <= len {
+            let header = &self.buf[offset..offset + EVENT_HEADER];
+            let name_len = u32::from_ne_bytes(header[12..16].try_into().unwrap()) as usize;
+            let name = &self.buf[offset + EVENT_HEADER..offset + EVENT_HEADER + name_len];
+            offset += EVENT_HEADER + name_len;
+
+            // The name is padded with NUL bytes
+            let name = name.split(|&b| b == 0).next().unwrap_or_default();
+            if !name.is_empty() {
+                let name = PathBuf::from(std::ffi::OsStr::from_bytes(name));
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// Wait until nobody has `path` open for writing and its size stopped changing.
+fn wait_until_finished(path: &Path, settle: Duration) {
+    let poll = Duration::from_secs(1);
+    let mut last_size = None;
+    let mut stable_for = Duration::ZERO;
+
+    loop {
+        let size = fs::metadata(path).map(|m| m.len()).ok();
+        if size.is_some() && size == last_size && !is_open_for_writing(path) {
+            stable_for += poll;
+            if stable_for >= settle {
+                return;
+            }
+        } else {
+            stable_for = Duration::ZERO;
+        }
+        last_size = size;
+        thread::sleep(poll);
+    }
+}
+
+/// Look through `/proc` for a process holding `path` open with write access.
+fn is_open_for_writing(path: &Path) -> bool {
+    let Ok(target) = fs::canonicalize(path) else {
+        return false;
+    };
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return false;
+    };
+
+    for process in processes.flatten() {
+        let proc_dir = process.path();
+        let Ok(fds) = fs::read_dir(proc_dir.join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            if fs::read_link(fd.path()).ok().as_deref() != Some(target.as_path()) {
+                continue;
+            }
+            let fdinfo = proc_dir.join("fdinfo").join(fd.file_name());
+            let flags = fs::read_to_string(fdinfo)
+                .ok()
+                .and_then(|info| {
+                    info.lines()
+                        .find_map(|l| l.strip_prefix("flags:"))
+                        .and_then(|f| u32::from_str_radix(f.trim(), 8).ok())
+                })
+                .unwrap_or(0);
+            // O_WRONLY or O_RDWR
+            if flags & 0b11 != 0 {
+                return true;
+            }
+        }
+    }
+    false
+}